@@ -0,0 +1,119 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use libc;
+
+/// Path of the temporary file `target` is written through. It lives in
+/// the same directory so that the final rename cannot cross filesystems.
+fn temp_path(target: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(target.file_name().unwrap_or("untitled".as_ref()));
+    name.push(format!(".kilo-{}.tmp", unsafe { libc::getpid() }));
+
+    target.with_file_name(name)
+}
+
+fn backup_path(target: &Path) -> PathBuf {
+    let mut name = target.as_os_str().to_owned();
+    name.push("~");
+    PathBuf::from(name)
+}
+
+/// Write all of `contents` to `file`, reporting how far it got if an
+/// error occurs part of the way through.
+fn write_counted(file: &mut fs::File, contents: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+
+    while written < contents.len() {
+        match file.write(&contents[written..]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    format!("wrote 0 bytes ({} bytes written)", written)));
+            },
+            Ok(n) => written += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("{} ({} bytes written)", e, written)));
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn write_temp(temp: &Path, contents: &[u8], orig: Option<&fs::Metadata>) -> io::Result<()> {
+    let mode = orig.map_or(0o666, |m| m.mode());
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(temp)?;
+
+    // The mode passed to open() is filtered through the umask.
+    if let Some(meta) = orig {
+        fs::set_permissions(temp, meta.permissions())?;
+    }
+
+    write_counted(&mut file, contents)?;
+    file.sync_all()
+}
+
+/// Replace the file at `path` with `contents` such that a crash at any
+/// point leaves either the old or the new version on disk, never a
+/// truncated mix. If `backup` is set, the previous version is kept
+/// next to it as `path~`.
+pub fn write_atomically(path: &Path, contents: &[u8], backup: bool) -> io::Result<()> {
+    // Write through symlinks rather than replacing them.
+    let target = match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_symlink() => fs::canonicalize(path)?,
+        _ => path.to_owned(),
+    };
+
+    let orig = match fs::metadata(&target) {
+        Ok(meta) => Some(meta),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    let temp = temp_path(&target);
+
+    if let Err(e) = write_temp(&temp, contents, orig.as_ref()) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+
+    if backup && orig.is_some() {
+        let backup = backup_path(&target);
+        let _ = fs::remove_file(&backup);
+
+        if fs::hard_link(&target, &backup).is_err() {
+            if let Err(e) = fs::copy(&target, &backup) {
+                let _ = fs::remove_file(&temp);
+                return Err(e);
+            }
+        }
+    }
+
+    if let Err(e) = fs::rename(&temp, &target) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+
+    // Make the rename itself durable.
+    if let Some(dir) = target.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+
+        if let Ok(dir) = fs::File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use std::env;
+use std::io;
+use std::time::Duration;
+
+use shell;
+
+/// How long to wait for a clipboard tool to paste, so that a slow X
+/// server can't hang the editor
+const PASTE_TIMEOUT_MS: u64 = 1000;
+
+const BASE64_CHARS: &'static [u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity((bytes.len() + 2) / 3 * 4);
+
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (n >> (18 - 6 * i)) & 0x3f;
+                result.push(BASE64_CHARS[index as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+
+    result
+}
+
+/// The OSC 52 sequence asking the terminal to put `text` on the system
+/// clipboard. This reaches the local machine over SSH and through tmux
+/// if the terminal allows it.
+pub fn osc52(text: &str) -> Vec<u8> {
+    format!("\x1b]52;c;{}\x07", base64(text.as_bytes())).into_bytes()
+}
+
+/// Shell commands that copy stdin to the system clipboard and paste it
+/// to stdout
+#[derive(Debug)]
+pub struct Tool {
+    // Environment variable that must be set for the tool to work
+    needs_env: Option<&'static str>,
+    copy: &'static str,
+    paste: &'static str,
+}
+
+const TOOLS: &'static [Tool] = &[
+    Tool {
+        needs_env: Some("WAYLAND_DISPLAY"),
+        copy: "wl-copy",
+        paste: "wl-paste --no-newline",
+    },
+    Tool {
+        needs_env: Some("DISPLAY"),
+        copy: "xclip -selection clipboard",
+        paste: "xclip -selection clipboard -o",
+    },
+    Tool {
+        needs_env: Some("DISPLAY"),
+        copy: "xsel --clipboard --input",
+        paste: "xsel --clipboard --output",
+    },
+    Tool {
+        needs_env: None,
+        copy: "pbcopy",
+        paste: "pbpaste",
+    },
+];
+
+fn in_path(program: &str) -> bool {
+    env::var_os("PATH").map_or(false, |paths| {
+        env::split_paths(&paths).any(|dir| dir.join(program).is_file())
+    })
+}
+
+/// The first clipboard tool that is installed and usable here
+pub fn find_tool() -> Option<&'static Tool> {
+    TOOLS.iter().find(|tool| {
+        let program = tool.copy.split(' ').next().unwrap();
+
+        tool.needs_env.map_or(true, |var| env::var_os(var).is_some())
+            && in_path(program)
+    })
+}
+
+pub fn copy(tool: &Tool, text: &str) -> io::Result<()> {
+    shell::pipe_to(tool.copy, text)
+}
+
+pub fn paste(tool: &Tool) -> io::Result<String> {
+    let timeout = Duration::from_millis(PASTE_TIMEOUT_MS);
+
+    shell::filter_timeout(tool.paste, "", timeout)
+        .map(|text| text.replace("\r\n", "\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::base64;
+
+    #[test]
+    fn base64_pads_short_chunks() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"M"), "TQ==");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"Man"), "TWFu");
+    }
+
+    #[test]
+    fn base64_of_multibyte_text() {
+        assert_eq!(base64("héllo\n".as_bytes()), "aMOpbGxvCg==");
+        assert_eq!(base64(&[0xff, 0xfe, 0xfd, 0x00]), "//79AA==");
+    }
+}
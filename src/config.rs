@@ -0,0 +1,348 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+use file_format::LineEnding;
+use glob;
+use syntax::Colors;
+
+/// What the gutter shows beside each line
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LineNumbers {
+    Off,
+    Absolute,
+    // Distance from the cursor line, which shows its own number
+    Relative,
+}
+
+/// Settings that can be changed without recompiling, globally or for
+/// files matching a pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub tab_width: usize,
+    // Insert spaces instead of a tab character
+    pub soft_tabs: bool,
+    // Columns per soft tab, if not `tab_width`
+    pub indent_size: Option<usize>,
+    // How to write the file, if not the way it was read
+    pub end_of_line: Option<LineEnding>,
+    pub final_newline: Option<bool>,
+    pub bom: Option<bool>,
+    pub trim_trailing_whitespace: bool,
+    // Keep the previous version of a file as `file~` when saving
+    pub backup: bool,
+    pub status_bar: bool,
+    // Break long lines across screen lines instead of scrolling sideways
+    pub soft_wrap: bool,
+    pub line_numbers: LineNumbers,
+    // Mark lines edited since the last save in the gutter
+    pub change_marks: bool,
+    pub colors: Colors,
+    // What toggle-comment inserts, if not the one for the file's syntax
+    pub line_comment: Option<String>,
+    pub search_ignore_case: bool,
+    // Ignore case unless the search text has an uppercase letter
+    pub search_smart_case: bool,
+    pub search_whole_word: bool,
+    // Continue from the other end of the buffer
+    pub search_wrap: bool,
+    // Send copied text to the terminal's clipboard with OSC 52
+    pub clipboard_osc52: bool,
+    // Longest text in bytes to send with OSC 52
+    pub clipboard_osc52_limit: usize,
+    // Copy and paste with tools such as xclip when they are installed
+    pub clipboard_tools: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            tab_width: 8,
+            soft_tabs: false,
+            indent_size: None,
+            end_of_line: None,
+            final_newline: None,
+            bom: None,
+            trim_trailing_whitespace: false,
+            backup: false,
+            status_bar: true,
+            soft_wrap: false,
+            line_numbers: LineNumbers::Off,
+            change_marks: false,
+            colors: Default::default(),
+            line_comment: None,
+            search_ignore_case: false,
+            search_smart_case: false,
+            search_whole_word: false,
+            search_wrap: true,
+            clipboard_osc52: true,
+            clipboard_osc52_limit: 100000,
+            clipboard_tools: true,
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("expected true or false, not `{}`", value)),
+    }
+}
+
+fn parse_width(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("expected a positive number, not `{}`", value)),
+    }
+}
+
+fn parse_line_ending(value: &str) -> Result<LineEnding, String> {
+    match value {
+        "lf" => Ok(LineEnding::Lf),
+        "crlf" => Ok(LineEnding::CrLf),
+        _ => Err(format!("unsupported line ending `{}`", value)),
+    }
+}
+
+/// Whether to write a byte order mark for `charset`
+fn parse_charset(value: &str) -> Result<bool, String> {
+    match value {
+        "utf-8" => Ok(false),
+        "utf-8-bom" => Ok(true),
+        _ => Err(format!("unsupported charset `{}`", value)),
+    }
+}
+
+fn parse_line_numbers(value: &str) -> Result<LineNumbers, String> {
+    match value {
+        "off" => Ok(LineNumbers::Off),
+        "absolute" => Ok(LineNumbers::Absolute),
+        "relative" => Ok(LineNumbers::Relative),
+        _ => Err(format!("expected off, absolute or relative, not `{}`", value)),
+    }
+}
+
+const COLOR_NAMES: &'static [(&'static str, u8)] = &[
+    ("default", 0),
+    ("black",   30),
+    ("red",     31),
+    ("green",   32),
+    ("yellow",  33),
+    ("blue",    34),
+    ("magenta", 35),
+    ("cyan",    36),
+    ("white",   37),
+];
+
+/// Parse a color name such as `cyan` or `bright-red`, or a raw SGR code.
+fn parse_color(value: &str) -> Result<u8, String> {
+    let (name, bright) = if value.starts_with("bright-") {
+        (&value["bright-".len()..], true)
+    } else {
+        (value, false)
+    };
+
+    let named = COLOR_NAMES.iter()
+        .find(|&&(n, _)| n == name)
+        .map(|&(_, code)| code);
+
+    match (named, value.parse()) {
+        (Some(0), _) if bright => Err(format!("unknown color `{}`", value)),
+        (Some(code), _) if bright => Ok(code + 60),
+        (Some(code), _) => Ok(code),
+        (None, Ok(code)) => Ok(code),
+        (None, Err(_)) => Err(format!("unknown color `{}`", value)),
+    }
+}
+
+impl Settings {
+    /// Change the setting called `name` to `value`.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "tab_width"      => self.tab_width = parse_width(value)?,
+            "soft_tabs"      => self.soft_tabs = parse_bool(value)?,
+            "indent_style"   => self.soft_tabs = match value {
+                "space" => true,
+                "tab" => false,
+                _ => return Err(format!("expected space or tab, not `{}`", value)),
+            },
+            "indent_size"    => self.indent_size = match value {
+                "tab" => None,
+                _ => Some(parse_width(value)?),
+            },
+            "end_of_line"    => self.end_of_line = Some(parse_line_ending(value)?),
+            "insert_final_newline" =>
+                self.final_newline = Some(parse_bool(value)?),
+            "trim_trailing_whitespace" =>
+                self.trim_trailing_whitespace = parse_bool(value)?,
+            "charset"        => self.bom = Some(parse_charset(value)?),
+            "backup"         => self.backup = parse_bool(value)?,
+            "status_bar"     => self.status_bar = parse_bool(value)?,
+            "soft_wrap"      => self.soft_wrap = parse_bool(value)?,
+            "line_numbers"   => self.line_numbers = parse_line_numbers(value)?,
+            "change_marks"   => self.change_marks = parse_bool(value)?,
+            "color.normal"   => self.colors.normal = parse_color(value)?,
+            "color.comment"  => self.colors.comment = parse_color(value)?,
+            "color.keyword1" => self.colors.keyword1 = parse_color(value)?,
+            "color.keyword2" => self.colors.keyword2 = parse_color(value)?,
+            "color.string"   => self.colors.string = parse_color(value)?,
+            "color.number"   => self.colors.number = parse_color(value)?,
+            "color.change"   => self.colors.change = parse_color(value)?,
+            "line_comment"   => self.line_comment = match value {
+                "" => None,
+                _ => Some(value.to_owned()),
+            },
+            "search.ignore_case" =>
+                self.search_ignore_case = parse_bool(value)?,
+            "search.smart_case" =>
+                self.search_smart_case = parse_bool(value)?,
+            "search.whole_word" =>
+                self.search_whole_word = parse_bool(value)?,
+            "search.wrap"    => self.search_wrap = parse_bool(value)?,
+            "clipboard.osc52" =>
+                self.clipboard_osc52 = parse_bool(value)?,
+            "clipboard.osc52_limit" =>
+                self.clipboard_osc52_limit = parse_width(value)?,
+            "clipboard.tools" =>
+                self.clipboard_tools = parse_bool(value)?,
+            _ => return Err(format!("unknown setting `{}`", name)),
+        }
+
+        Ok(())
+    }
+
+    /// Columns per soft tab
+    pub fn indent_width(&self) -> usize {
+        self.indent_size.unwrap_or(self.tab_width)
+    }
+}
+
+/// Settings from one section of the config file. Those before the first
+/// section header have no pattern and apply to every file.
+#[derive(Debug)]
+struct Section {
+    pattern: Option<String>,
+    settings: Vec<(String, String)>,
+}
+
+/// Whether a section pattern such as `*.go` or `Makefile` applies to
+/// `path`. Patterns without a slash are matched against the file name.
+fn section_matches(pattern: &str, path: &Path) -> bool {
+    if !pattern.contains('/') {
+        return path.file_name().map_or(false, |name| {
+            glob::matches(pattern, &name.to_string_lossy())
+        });
+    }
+
+    let path = if path.is_absolute() {
+        path.to_owned()
+    } else {
+        match env::current_dir() {
+            Ok(dir) => dir.join(path),
+            Err(_) => path.to_owned(),
+        }
+    };
+
+    let pattern = format!("**/{}", pattern.trim_left_matches('/'));
+    glob::matches(&pattern, &path.to_string_lossy())
+}
+
+/// The contents of the config file: an INI file whose section headers
+/// are glob patterns, as in
+///
+/// ```ini
+/// tab_width = 4
+///
+/// [Makefile]
+/// soft_tabs = false
+/// ```
+#[derive(Debug, Default)]
+pub struct Config {
+    sections: Vec<Section>,
+}
+
+impl Config {
+    /// Read the config file at `path`. Returns a message for each line
+    /// that could not be used along with the config.
+    pub fn read_file(path: &Path) -> io::Result<(Config, Vec<String>)> {
+        let mut text = String::new();
+        fs::File::open(path)?.read_to_string(&mut text)?;
+
+        let file_name = path.file_name()
+            .map_or("config".into(), |name| name.to_string_lossy());
+
+        let mut config = Config {
+            sections: vec![Section {pattern: None, settings: vec![]}],
+        };
+        let mut errors = vec![];
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                let pattern = line[1..line.len() - 1].trim();
+
+                config.sections.push(Section {
+                    pattern: Some(pattern.to_owned()),
+                    settings: vec![],
+                });
+                continue;
+            }
+
+            let result = match line.find('=') {
+                Some(j) => {
+                    let name = line[..j].trim();
+                    let value = line[j + 1..].trim();
+
+                    // Check the value now so that mistakes are reported
+                    // once, at startup.
+                    Settings::default().set(name, value).map(|_| (name, value))
+                },
+                None => Err(format!("expected `NAME = VALUE`: {}", line)),
+            };
+
+            match result {
+                Ok((name, value)) => {
+                    let section = config.sections.last_mut().unwrap();
+                    section.settings.push((name.to_owned(), value.to_owned()));
+                },
+                Err(msg) => errors.push(format!("{}:{}: {}", file_name, i + 1, msg)),
+            }
+        }
+
+        Ok((config, errors))
+    }
+
+    /// Apply the settings that are meant for `path` in the order they
+    /// appear, so later sections override earlier ones.
+    pub fn apply(&self, settings: &mut Settings, path: Option<&Path>) {
+        for section in &self.sections {
+            let applies = match (&section.pattern, path) {
+                (&None, _) => true,
+                (&Some(ref pattern), Some(path)) => section_matches(pattern, path),
+                (&Some(_), None) => false,
+            };
+
+            if applies {
+                for &(ref name, ref value) in &section.settings {
+                    let _ = settings.set(name, value);
+                }
+            }
+        }
+    }
+}
+
+/// Location of the user's config file called `name`, normally in
+/// `~/.config/kilo_rust/`.
+pub fn user_config_path(name: &str) -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::home_dir().map(|home| home.join(".config")));
+
+    config_home.map(|dir| dir.join("kilo_rust").join(name))
+}
@@ -1,13 +1,27 @@
+use std::collections::HashMap;
 use std::default::Default;
-use std::io::BufRead;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
 use std::time::Instant;
-use std::{io, fs};
-
+use std::{cmp, io, fs, mem};
+
+use atomic_write;
+use clipboard;
+use config::{self, Config, LineNumbers, Settings};
+use editorconfig;
+use file_format::{self, FileFormat, LineEnding};
+use keymap::{self, Command, Context, Keymap, Lookup};
+use kill_ring::KillRing;
 use libc;
 use low_level;
-use read_key;
+use read_key::{self, Input};
+use search::{self, Query};
+use shell;
 use syntax;
+use undo;
+use unicode;
 
 fn uclamp(a: isize) -> usize {
     if a < 0 {
@@ -17,58 +31,17 @@ fn uclamp(a: isize) -> usize {
     }
 }
 
-fn isprint(b: u8) -> bool {
-    unsafe {
-        if libc::isprint(b as libc::c_int) != 0 {
-            true
-        } else {
-            false
-        }
-    }
-}
-
-/// Returns the first character index of the start of a substring
-/// searching from a given character index.
-fn find_char(s: &str, query: &str, from_char: usize) -> Option<usize> {
-    let from_byte_index = s.char_indices().nth(from_char);
-    if let Some((from_byte_index, _)) = from_byte_index {
-        let slice = &s[from_byte_index..];
-
-        if let Some(match_byte_index) = slice.find(query) {
-            let match_char_index = slice.char_indices()
-                .position(|(i, _)| i == match_byte_index)
-                .expect("byte did not correspond to char");
-
-            return Some(match_char_index + from_char);
-        }
-    }
-
-    None
-}
-
-/// Returns the last character index of the start of a substring
-/// searching backwards from a given character index.
-fn rfind_char(s: &str, query: &str, to_char: usize) -> Option<usize> {
-    let to_byte_index = s.char_indices().nth(to_char);
-    if let Some((to_byte_index, _)) = to_byte_index {
-        let slice = &s[..to_byte_index];
-
-        if let Some(match_byte_index) = slice.rfind(query) {
-            let match_char_index = slice.char_indices()
-                .position(|(i, _)| i == match_byte_index)
-                .expect("byte did not correspond to char");
-
-            return Some(match_char_index);
-        }
-    }
-
-    None
+/// Byte index of the char at `char_index`, or the end of `s`.
+pub fn byte_index(s: &str, char_index: usize) -> usize {
+    s.char_indices()
+        .nth(char_index)
+        .map_or(s.len(), |(i, _)| i)
 }
 
 #[derive(Debug, Eq, PartialEq, Default, Clone, Copy)]
-struct Pos {
-    x: usize,
-    y: usize,
+pub struct Pos {
+    pub x: usize,
+    pub y: usize,
 }
 
 fn pos(x: usize, y: usize) -> Pos {
@@ -111,11 +84,48 @@ fn get_window_size() -> io::Result<Pos> {
             y: ws.ws_row as usize})
 }
 
+/// Size of the window minus the status bar and message line
+fn get_screen_size(status_rows: usize) -> io::Result<Pos> {
+    let mut screen = get_window_size()?;
+    screen.y = cmp::max(screen.y.saturating_sub(status_rows), 1);
+
+    Ok(screen)
+}
+
+static WINDOW_RESIZED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn handle_sigwinch(_: libc::c_int) {
+    WINDOW_RESIZED.store(true, Ordering::SeqCst);
+}
+
+/// Column after drawing `ch` starting at column `col`
+fn next_column(col: usize, ch: char, tab_width: usize) -> usize {
+    if ch == '\t' {
+        col + tab_width - col % tab_width
+    } else if ch.is_control() {
+        col + 2
+    } else {
+        col + unicode::char_width(ch)
+    }
+}
+
+/// The letter of `^A`-style notation for a control character
+fn caret_notation(ch: char) -> char {
+    match ch as u32 {
+        c @ 0...0x7f => ((c as u8) ^ 0x40) as char,
+        _ => '?',
+    }
+}
+
 #[derive(Default)]
 struct Row {
     text: String,
     render: String,
     highlight: Option<syntax::HighlightResult>,
+    // Whether the line ends in CRLF when the buffer has mixed endings
+    crlf: bool,
+    // Edited since the file was last saved
+    changed: bool,
 }
 
 impl Row {
@@ -123,94 +133,778 @@ impl Row {
         Default::default()
     }
 
-    fn update(&mut self, text: String) {
+    fn update(&mut self, text: String, tab_width: usize) {
         self.text = text;
         self.render.clear();
 
+        let mut col = 0;
+
         for ch in self.text.chars() {
-            if ch == '\t' {
-                self.render.push(' ');
+            let next_col = next_column(col, ch, tab_width);
 
-                while self.render.len() % 8 != 0 {
+            if ch == '\t' {
+                for _ in col..next_col {
                     self.render.push(' ');
                 }
+            } else if ch.is_control() {
+                self.render.push('^');
+                self.render.push(caret_notation(ch));
             } else {
                 self.render.push(ch);
             }
+
+            col = next_col;
         }
 
         self.highlight = None;
     }
+
+    /// Screen column at which the char at `x` is drawn
+    fn render_x(&self, x: usize, tab_width: usize) -> usize {
+        self.text.chars()
+            .take(x)
+            .fold(0, |col, ch| next_column(col, ch, tab_width))
+    }
+
+    /// Index of the char drawn at screen column `col`, or the length of
+    /// the line if `col` is past its end
+    fn char_at_column(&self, col: usize, tab_width: usize) -> usize {
+        let mut next_col = 0;
+
+        for (x, ch) in self.text.chars().enumerate() {
+            next_col = next_column(next_col, ch, tab_width);
+
+            if next_col > col {
+                return x;
+            }
+        }
+
+        self.char_count()
+    }
+
+    /// Index into `render` of the char at `x`
+    fn render_index(&self, x: usize, tab_width: usize) -> usize {
+        let mut col = 0;
+        let mut index = 0;
+
+        for ch in self.text.chars().take(x) {
+            let next_col = next_column(col, ch, tab_width);
+
+            index += if ch == '\t' {
+                next_col - col
+            } else if ch.is_control() {
+                2
+            } else {
+                1
+            };
+
+            col = next_col;
+        }
+
+        index
+    }
+
+    /// Length of the text in chars, which is what `Pos::x` counts
+    fn char_count(&self) -> usize {
+        self.text.chars().count()
+    }
+
+    /// Byte index of the char at `x`, or the end of the text
+    fn byte_index(&self, x: usize) -> usize {
+        byte_index(&self.text, x)
+    }
+}
+
+/// A marker drawn in the gutter beside a line
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Sign {
+    Changed,
+}
+
+impl Sign {
+    fn symbol(self) -> char {
+        match self {
+            Sign::Changed => '*',
+        }
+    }
+
+    fn color(self, colors: &syntax::Colors) -> u8 {
+        match self {
+            Sign::Changed => colors.change,
+        }
+    }
+}
+
+/// How many earlier searches the find prompt remembers
+const SEARCH_HISTORY_SIZE: usize = 100;
+
+/// How a search from the cursor ended
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum SearchResult {
+    NotFound,
+    Found,
+    // Found after continuing from the other end of the buffer
+    Wrapped,
+}
+
+/// Styling drawn over the syntax colors
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Mark {
+    // The match the cursor is on, drawn over the others
+    CurrentMatch,
+    // Drawn over the region so that they can be seen in it
+    Match,
+    // Between the mark and the cursor
+    Region,
+}
+
+impl Mark {
+    /// SGR parameters to append for the mark
+    fn sgr(self) -> &'static str {
+        match self {
+            Mark::CurrentMatch => ";1;30;43", // Bold black on yellow
+            Mark::Match => ";7", // Inverse video
+            Mark::Region => ";30;46", // Black on cyan
+        }
+    }
+}
+
+/// Break `row` into screen lines at most `width` columns wide, before a
+/// word where possible. Returns the start and end column of each line.
+/// Spaces may run past the edge rather than start a line, and a line
+/// that fills its width is followed by an empty one for the cursor to
+/// sit on at the end of the row.
+fn wrap_row(row: &Row, width: usize) -> Vec<(usize, usize)> {
+    let width = cmp::max(width, 1);
+    let mut lines = vec![];
+    let mut start = 0;
+    let mut col = 0;
+    // Column of the last word to begin on the current line
+    let mut word_start = None;
+    let mut after_space = false;
+
+    for ch in row.render.chars() {
+        let char_width = unicode::char_width(ch);
+
+        if ch == ' ' {
+            after_space = true;
+        } else if char_width > 0 {
+            if after_space {
+                word_start = Some(col);
+                after_space = false;
+            }
+
+            while col + char_width > start + width && col > start {
+                let end = match word_start {
+                    Some(word_start) if word_start > start => word_start,
+                    _ => col,
+                };
+
+                lines.push((start, end));
+                start = end;
+            }
+        }
+
+        col += char_width;
+    }
+
+    lines.push((start, col));
+
+    if col - start >= width {
+        lines.push((col, col));
+    }
+
+    lines
+}
+
+/// Index into `lines` from `wrap_row` of the screen line showing `col`
+fn wrap_line_of(lines: &[(usize, usize)], col: usize) -> usize {
+    lines.iter().rposition(|&(start, _)| start <= col).unwrap_or(0)
+}
+
+/// Append the columns of `row` from `start` to `start + width` to `buf`,
+/// with syntax colors. Wide characters cut by either edge are drawn as
+/// spaces.
+fn draw_row(buf: &mut Vec<u8>, row: &Row, start: usize, width: usize,
+            colors: &syntax::Colors, marks: &[(usize, usize, Mark)]) {
+    let end = start + width;
+    let highlight = row.highlight.as_ref().map(|hl| &hl.highlight[..]);
+
+    let mut col = 0;
+    let mut current_style = (None, None);
+    // Whether the last char was drawn, so its combining marks can be too
+    let mut drawn = false;
+
+    for (i, ch) in row.render.chars().enumerate() {
+        let char_width = unicode::char_width(ch);
+
+        if char_width == 0 {
+            if drawn {
+                buf.extend(ch.encode_utf8().as_slice());
+            }
+            continue;
+        }
+
+        let next_col = col + char_width;
+
+        if next_col <= start {
+            col = next_col;
+            drawn = false;
+            continue;
+        } else if col >= end {
+            break;
+        }
+
+        let color = highlight.map(|hl| hl[i].color(colors));
+        let mark = marks.iter()
+            .find(|&&(from, to, _)| from <= i && i < to)
+            .map(|&(_, _, mark)| mark);
+
+        if current_style != (color, mark) {
+            buf.extend(b"\x1b[0");
+            if let Some(color) = color {
+                buf.extend(format!(";{}", color).as_bytes());
+            }
+            if let Some(mark) = mark {
+                buf.extend(mark.sgr().as_bytes());
+            }
+            buf.push(b'm');
+
+            current_style = (color, mark);
+        }
+
+        if col < start || next_col > end {
+            let visible = cmp::min(next_col, end) - cmp::max(col, start);
+
+            for _ in 0..visible {
+                buf.push(b' ');
+            }
+
+            drawn = false;
+        } else {
+            buf.extend(ch.encode_utf8().as_slice());
+            drawn = true;
+        }
+
+        col = next_col;
+    }
+
+    if current_style != (None, None) {
+        buf.extend(b"\x1b[0m");
+    }
+}
+
+/// The text up to the first line break of a paste, for a prompt
+fn first_line(text: &str) -> &str {
+    text.split(|ch| ch == '\r' || ch == '\n').next().unwrap()
+}
+
+/// Whether `command` only moves the cursor, so that it extends a
+/// selection when Shift is held
+fn is_cursor_move(command: Command) -> bool {
+    use keymap::Command::*;
+
+    match command {
+        MoveUp | MoveDown | MoveLeft | MoveRight | PageUp | PageDown
+            | LineStart | LineEnd | WordLeft | WordRight => true,
+        _ => false,
+    }
+}
+
+/// Whether the region stays active after `command` changes the buffer
+fn keeps_mark(command: Command) -> bool {
+    use keymap::Command::*;
+
+    match command {
+        InsertTab | Indent | Unindent | ToggleComment | Upcase | Downcase => true,
+        _ => false,
+    }
+}
+
+/// Where `p` ends up after `remove` chars at `x` on row `y` are replaced
+/// by `insert` chars
+fn shift_pos(p: Pos, y: usize, x: usize, remove: usize, insert: usize) -> Pos {
+    if p.y != y || p.x <= x {
+        p
+    } else {
+        pos(cmp::max(p.x, x + remove) - remove + insert, y)
+    }
 }
 
 #[derive(Default)]
 pub struct Editor {
     cursor: Pos,
+    // The other end of the region, if one is active
+    mark: Option<Pos>,
+    // Whether the mark was set by moving with Shift held, so that moving
+    // without it drops the mark again
+    shift_selecting: bool,
     // offset is always positive so use Pos, not Delta
     offset: Pos,
+    // With soft wrap, the first screen line of row `offset.y` to show
+    wrap_offset: usize,
+    // With soft wrap, the column within a screen line that moving up and
+    // down keeps to
+    wrap_goal: Option<usize>,
     screen: Pos,
 
     orig_termios: Option<libc::termios>,
 
     rows: Vec<Row>,
-
-    // dirty: bool,
+    // Bumped on every change to the text, so that what is worked out
+    // from it can tell when it is out of date
+    text_version: u64,
+
+    history: undo::History,
+    // History version at the last save
+    saved_version: u64,
+    // Set by a first Ctrl-Q on a modified buffer
+    quit_pending: bool,
+    make_backups: bool,
+    format: FileFormat,
+    saved_line_ending: LineEnding,
+    // Clear the whole terminal on the next refresh
+    full_redraw: bool,
+    // Escape sequences to send with the next refresh
+    pending_output: Vec<u8>,
+    // Where the screen is drawn, if not stdout
+    output: Option<Box<io::Write>>,
 
     file_path: Option<PathBuf>,
     status_msg: Option<StatusMessage>,
     syntax: Option<syntax::Syntax>,
+    syntax_db: HashMap<String, Rc<syntax::Syntax>>,
+
+    config: Config,
+    // The config applied to the current file
+    settings: Settings,
+    // The .editorconfig files that contributed to `settings`
+    editorconfig_files: Vec<PathBuf>,
+
+    keymap: Keymap,
+    // Start and end column of the match found by find
+    search_match: Option<(Pos, usize)>,
+    // What find is looking for, so that every match can be shown
+    search_query: Option<Rc<Query>>,
+    // Where a query matches, as (row, column) in order, and the text
+    // version they were found in
+    match_cache: Option<(Rc<Query>, u64, Vec<(usize, usize)>)>,
+    // Earlier searches, oldest first
+    search_history: Vec<String>,
+    // What the find prompt's toggles are set to, starting out as the
+    // settings say and changing with them. Regex mode is chosen afresh
+    // for each search.
+    search_options: search::Options,
+    search_wrap: bool,
+    kill_ring: KillRing,
+    // Whether the last cut left a kill that the next one may add to
+    kill_appendable: bool,
+    // Start and end of the text inserted by the last paste
+    yank_range: Option<(Pos, Pos)>,
+
+    escape_reader: read_key::EscapeReader,
+    // Keys of a multi-key binding typed so far
+    pending_keys: Vec<read_key::Key>,
+    // What the last complete key sequence did, so that a cut or paste
+    // can carry on from it
+    last_command: Option<Command>,
 }
 
 impl Editor {
     pub fn new() -> io::Result<Editor> {
-        let mut screen = get_window_size()?;
-        screen.y -= 2;          // for status bar
+        low_level::set_signal_handler(libc::SIGWINCH, handle_sigwinch)?;
 
         let mut result: Editor = Default::default();
-        result.screen = screen;
+        result.screen = get_screen_size(result.status_rows())?;
         result.cursor.x = 1;
         result.syntax = None;
+        result.syntax_db = syntax::make_syntax_db();
+        result.search_wrap = result.settings.search_wrap;
 
         Ok(result)
     }
 
-    pub fn set_syntax(&mut self, syntax: Option<syntax::Syntax>) {
+    fn set_syntax(&mut self, syntax: Option<syntax::Syntax>) {
         self.syntax = syntax;
+
+        for row in &mut self.rows {
+            row.highlight = None;
+        }
+
         self.update_row_highlight(0);
     }
 
-    pub fn open(&mut self, path: &Path) -> io::Result<()> {
-        let file = fs::File::open(path)?;
-        let reader = io::BufReader::new(file);
+    /// Pick the syntax matching the extension of `file_path`.
+    fn select_syntax(&mut self) {
+        let syntax = self.file_path.as_ref()
+            .and_then(|p| p.extension())
+            .and_then(|ext| self.syntax_db.get(&*ext.to_string_lossy()))
+            .map(|s| (**s).clone());
 
-        self.rows.clear();
+        self.set_syntax(syntax);
+    }
 
-        for line in reader.lines() {
-            let row = Row::new();
-            self.rows.push(row);
-            let new_index = self.rows.len() - 1;
-            self.update_row(new_index, line?);
+    /// Load the file at `path` into the buffer. A path that does not
+    /// exist yet opens an empty buffer that will be created on save.
+    pub fn open(&mut self, path: &Path) -> io::Result<()> {
+        self.rows.clear();
+        self.text_version += 1;
+        self.format = Default::default();
+        let mut new_file = false;
+
+        match fs::File::open(path) {
+            Ok(mut file) => {
+                let mut bytes = vec![];
+                file.read_to_end(&mut bytes)?;
+
+                let (format, lines) = file_format::decode(bytes)?;
+                self.format = format;
+
+                for (text, crlf) in lines {
+                    let mut row = Row::new();
+                    row.update(text, self.settings.tab_width);
+                    row.crlf = crlf;
+                    self.rows.push(row);
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => new_file = true,
+            Err(e) => return Err(e),
         }
 
         self.file_path = Some(PathBuf::from(path));
+        self.history = Default::default();
+        self.saved_version = self.history.version();
+        self.saved_line_ending = self.format.line_ending;
+        self.apply_settings();
+
+        if new_file {
+            self.set_first_status_message("New file".to_owned());
+        }
 
         Ok(())
     }
 
-    fn save(&mut self) -> io::Result<()> {
-        use std::io::Write;
+    fn is_dirty(&self) -> bool {
+        self.history.version() != self.saved_version
+            || self.format.line_ending != self.saved_line_ending
+    }
+
+    /// Convert the whole buffer to the next line ending style. A file
+    /// that was saved with mixed endings can be cycled back to them, as
+    /// each row keeps its own until the next save.
+    fn cycle_line_ending(&mut self) {
+        let line_ending = match self.format.line_ending {
+            LineEnding::Lf => LineEnding::CrLf,
+            LineEnding::CrLf if self.saved_line_ending == LineEnding::Mixed =>
+                LineEnding::Mixed,
+            LineEnding::CrLf | LineEnding::Mixed => LineEnding::Lf,
+        };
+
+        self.format.line_ending = line_ending;
+        self.set_status_message(
+            format!("Line endings: {}", line_ending.name()));
+    }
+
+    /// Save the buffer to `file_path`, asking for a path first if there
+    /// is none yet or if `save_as` is set.
+    fn save(&mut self, save_as: bool) {
+        self.history.seal();
+
+        let current = self.file_path.clone();
+
+        let path = match current {
+            Some(ref path) if !save_as => path.clone(),
+            _ => {
+                let initial = current.as_ref()
+                    .map(|p| p.to_string_lossy().into_owned())
+                    .unwrap_or(String::new());
+
+                match self.prompt("Save as: ", &initial, false) {
+                    Ok(Some(name)) => PathBuf::from(name),
+                    Ok(None) => {
+                        self.set_status_message("Save aborted".to_owned());
+                        return;
+                    },
+                    Err(e) => {
+                        self.set_status_message(format!("Error: {}", e));
+                        return;
+                    },
+                }
+            },
+        };
+
+        let is_new_path = self.file_path.as_ref() != Some(&path);
 
-        let path = self.file_path.clone().unwrap();
-        let file = fs::File::create(path)?;
-        let mut writer = io::BufWriter::new(file);
+        if is_new_path && path.exists() {
+            let question = format!("{} exists. Overwrite? (y/n)",
+                                   path.to_string_lossy());
 
-        for row in &self.rows {
-            let text: &str = &row.text;
-            writer.write_all(text.as_bytes())?;
-            writer.write_all(b"\n")?;
+            match self.confirm(&question) {
+                Ok(true) => (),
+                Ok(false) => {
+                    self.set_status_message("Save aborted".to_owned());
+                    return;
+                },
+                Err(e) => {
+                    self.set_status_message(format!("Error: {}", e));
+                    return;
+                },
+            }
         }
 
-        Ok(())
+        let trimmed = self.settings.trim_trailing_whitespace
+            && self.trim_trailing_whitespace();
+
+        let written = match self.write_to(&path) {
+            Ok(written) => written,
+            Err(e) => {
+                // Leave the buffer as it was before the save
+                if trimmed {
+                    if let Some(step) = self.history.take_undo() {
+                        self.revert(&step);
+                        self.clear_change_marks_if_saved();
+                    }
+                }
+
+                self.set_status_message(
+                    format!("Can't save! I/O error: {}", e));
+                return;
+            },
+        };
+
+        if is_new_path {
+            self.file_path = Some(path);
+            self.apply_settings();
+        }
+
+        self.set_status_message(
+            format!("{} bytes written to disk", written));
+    }
+
+    /// Write the buffer to `path`, returning the number of bytes written.
+    fn write_to(&mut self, path: &Path) -> io::Result<usize> {
+        let format = self.save_format();
+        let contents = file_format::encode(
+            format,
+            self.rows.iter().map(|row| (&row.text[..], row.crlf)));
+
+        let backup = self.make_backups || self.settings.backup;
+        atomic_write::write_atomically(path, &contents, backup)?;
+
+        for row in &mut self.rows {
+            if format.line_ending != self.format.line_ending {
+                row.crlf = format.line_ending == LineEnding::CrLf;
+            }
+
+            row.changed = false;
+        }
+
+        self.format = format;
+        self.saved_version = self.history.version();
+        self.saved_line_ending = self.format.line_ending;
+
+        Ok(contents.len())
+    }
+
+    /// Report problems found in a config file on the status line.
+    fn report_config_errors(&mut self, path: &Path, result: io::Result<Vec<String>>) {
+        match result {
+            Ok(errors) => if let Some(first) = errors.first() {
+                let msg = if errors.len() > 1 {
+                    format!("{} (and {} more errors)", first, errors.len() - 1)
+                } else {
+                    first.clone()
+                };
+
+                self.set_first_status_message(msg);
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                let msg = format!("Error reading {}: {}", path.display(), e);
+                self.set_first_status_message(msg);
+            },
+        }
+    }
+
+    /// Apply the user's key bindings on top of the defaults.
+    pub fn load_keymap(&mut self) {
+        let path = match config::user_config_path("keys") {
+            Some(path) => path,
+            None => return,
+        };
+
+        let result = self.keymap.read_file(&path);
+        self.report_config_errors(&path, result);
+    }
+
+    /// Read the user's config file and apply it to the current file.
+    pub fn load_config(&mut self) {
+        let path = match config::user_config_path("config") {
+            Some(path) => path,
+            None => return,
+        };
+
+        let result = match Config::read_file(&path) {
+            Ok((config, errors)) => {
+                self.config = config;
+                Ok(errors)
+            },
+            Err(e) => Err(e),
+        };
+
+        self.report_config_errors(&path, result);
+        self.apply_settings();
+    }
+
+    /// Work out the settings and syntax for `file_path`.
+    fn apply_settings(&mut self) {
+        let mut settings = Settings::default();
+        self.config.apply(&mut settings, self.file_path.as_ref().map(|p| &**p));
+
+        let mut errors = vec![];
+        self.editorconfig_files.clear();
+
+        if let Some(ref path) = self.file_path {
+            match editorconfig::properties_for(path) {
+                Ok(properties) => {
+                    errors = properties.apply(&mut settings);
+                    self.editorconfig_files = properties.files;
+                },
+                Err(e) => errors.push(format!("Error reading .editorconfig: {}", e)),
+            }
+        }
+
+        if let Some(msg) = errors.into_iter().next() {
+            self.set_first_status_message(msg);
+        }
+
+        if settings.tab_width != self.settings.tab_width {
+            for row in &mut self.rows {
+                let text = mem::replace(&mut row.text, String::new());
+                row.update(text, settings.tab_width);
+            }
+        }
+
+        if settings.soft_wrap != self.settings.soft_wrap {
+            self.offset.x = 0;
+            self.wrap_offset = 0;
+        }
+
+        let status_bar_changed = settings.status_bar != self.settings.status_bar;
+
+        // A toggle stays as it was left unless its setting changes
+        if settings.search_ignore_case != self.settings.search_ignore_case {
+            self.search_options.ignore_case = settings.search_ignore_case;
+        }
+        if settings.search_smart_case != self.settings.search_smart_case {
+            self.search_options.smart_case = settings.search_smart_case;
+        }
+        if settings.search_whole_word != self.settings.search_whole_word {
+            self.search_options.whole_word = settings.search_whole_word;
+        }
+        if settings.search_wrap != self.settings.search_wrap {
+            self.search_wrap = settings.search_wrap;
+        }
+
+        self.settings = settings;
+        self.select_syntax();
+
+        if status_bar_changed {
+            if let Err(e) = self.update_screen_size() {
+                self.set_status_message(format!("Error: {}", e));
+            }
+        }
+    }
+
+    fn status_rows(&self) -> usize {
+        if self.settings.status_bar { 2 } else { 1 }
+    }
+
+    /// The format the file was read with, overridden by the settings.
+    fn save_format(&self) -> FileFormat {
+        let mut format = self.format;
+
+        if let Some(line_ending) = self.settings.end_of_line {
+            format.line_ending = line_ending;
+        }
+        if let Some(final_newline) = self.settings.final_newline {
+            format.final_newline = final_newline;
+        }
+        if let Some(bom) = self.settings.bom {
+            format.bom = bom;
+        }
+
+        format
+    }
+
+    /// Delete whitespace at the ends of lines as a single undo step.
+    fn trim_trailing_whitespace(&mut self) -> bool {
+        let cursor = self.cursor;
+        let offset = (self.offset, self.wrap_offset);
+        let mut trimmed = false;
+
+        self.history.begin_group();
+
+        for y in 0..self.rows.len() {
+            let (start, end) = {
+                let text = &self.rows[y].text;
+                (text.trim_right().chars().count(), text.chars().count())
+            };
+
+            if start < end {
+                self.delete_text(pos(start, y), pos(end, y));
+                trimmed = true;
+            }
+        }
+
+        self.history.end_group();
+
+        self.cursor = cursor;
+        self.offset = offset.0;
+        self.wrap_offset = offset.1;
+
+        trimmed
+    }
+
+    /// Describe the settings in effect for this file on the status line.
+    fn show_settings(&mut self) {
+        let msg = {
+            let s = &self.settings;
+            let format = self.save_format();
+
+            let indent = if s.soft_tabs {
+                format!("{} spaces", s.indent_width())
+            } else {
+                "tabs".to_owned()
+            };
+
+            let mut msg = format!(
+                "tab_width={} indent={} end_of_line={} final_newline={} \
+                 trim_trailing_whitespace={} charset={}",
+                s.tab_width,
+                indent,
+                format.line_ending.name(),
+                format.final_newline,
+                s.trim_trailing_whitespace,
+                if format.bom { "utf-8-bom" } else { "utf-8" });
+
+            if !self.editorconfig_files.is_empty() {
+                let files: Vec<String> = self.editorconfig_files.iter()
+                    .map(|f| f.to_string_lossy().into_owned())
+                    .collect();
+
+                msg.push_str(&format!(" (from {})", files.join(", ")));
+            }
+
+            msg
+        };
+
+        self.set_status_message(msg);
+    }
+
+    /// Keep the previous version of the file as `file~` when saving.
+    pub fn set_make_backups(&mut self, make_backups: bool) {
+        self.make_backups = make_backups;
     }
 
     pub fn enable_raw_mode(&mut self) -> io::Result<()> {
@@ -250,11 +944,13 @@ impl Editor {
 
         self.orig_termios = Some(orig_termios);
 
-        Ok(())
+        // Have pastes sent between CSI 200~ and CSI 201~
+        self.write_output(b"\x1b[?2004h")
     }
 
     fn disable_raw_mode(&mut self) -> io::Result<()> {
         if let Some(cooked) = self.orig_termios {
+            self.write_output(b"\x1b[?2004l")?;
             low_level::set_termios(libc::STDIN_FILENO, libc::TCSAFLUSH, &cooked)?;
             self.orig_termios = None;
         }
@@ -263,30 +959,146 @@ impl Editor {
     }
 
     fn visual_cursor_position(&self) -> Pos {
-        match self.rows.get(self.cursor.y) {
-            Some(row) => {
-                let mut x = 0;
-
-                for ch in row.text.chars()
-                        .skip(self.offset.x)
-                        .take(self.screen.x)
-                        .take(self.cursor.x) {
-                    if ch == '\t' {
-                        x += 1;
-
-                        while x % 8 != 0 {
-                            x += 1;
-                        }
-                    } else {
-                        x += 1;
-                    }
-                }
+        let x = match self.rows.get(self.cursor.y) {
+            Some(row) => row.render_x(self.fixup(self.cursor).x, self.settings.tab_width),
+            None => 0,
+        };
 
-                Pos {y: self.cursor.y - self.offset.y, x: x}
-            },
-            None => Pos {y: self.cursor.y - self.offset.y, x: 0},
-        }
-    }
+        if self.settings.soft_wrap {
+            let lines = self.wrap_lines(self.cursor.y);
+            let line = wrap_line_of(&lines, x);
+            let top = (self.offset.y, self.wrap_offset);
+
+            // Spaces past the edge are shown at the last column
+            let x = cmp::min(x - lines[line].0, self.text_width().saturating_sub(1));
+
+            return Pos {y: self.screen_lines_between(top, (self.cursor.y, line)),
+                        x: x + self.gutter_width()};
+        }
+
+        Pos {y: self.cursor.y - self.offset.y,
+             x: x.saturating_sub(self.offset.x) + self.gutter_width()}
+    }
+
+    /// The screen lines of row `y` with soft wrap
+    fn wrap_lines(&self, y: usize) -> Vec<(usize, usize)> {
+        match self.rows.get(y) {
+            Some(row) => wrap_row(row, self.text_width()),
+            None => vec![(0, 0)],
+        }
+    }
+
+    /// The screen line after `line`, a row and an index into its
+    /// `wrap_lines`
+    fn next_screen_line(&self, line: (usize, usize)) -> Option<(usize, usize)> {
+        let (y, k) = line;
+
+        if k + 1 < self.wrap_lines(y).len() {
+            Some((y, k + 1))
+        } else if y + 1 < self.rows.len() {
+            Some((y + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    /// The screen line before `line`
+    fn prev_screen_line(&self, line: (usize, usize)) -> Option<(usize, usize)> {
+        let (y, k) = line;
+
+        if k > 0 {
+            Some((y, k - 1))
+        } else if y > 0 {
+            Some((y - 1, self.wrap_lines(y - 1).len() - 1))
+        } else {
+            None
+        }
+    }
+
+    /// How many screen lines down `to` is from `from`
+    fn screen_lines_between(&self, from: (usize, usize), to: (usize, usize)) -> usize {
+        if from.0 == to.0 {
+            return to.1.saturating_sub(from.1);
+        }
+
+        let mut count = self.wrap_lines(from.0).len().saturating_sub(from.1);
+
+        for y in from.0 + 1..to.0 {
+            count += self.wrap_lines(y).len();
+        }
+
+        count + to.1
+    }
+
+    /// Columns taken by the signs and line numbers left of the text
+    fn gutter_width(&self) -> usize {
+        let signs = if self.settings.change_marks { 1 } else { 0 };
+        let digits = match self.settings.line_numbers {
+            LineNumbers::Off => 0,
+            _ => self.rows.len().to_string().len(),
+        };
+
+        if signs + digits == 0 {
+            0
+        } else {
+            cmp::min(signs + digits + 1, self.screen.x)
+        }
+    }
+
+    /// Columns left for the text beside the gutter
+    fn text_width(&self) -> usize {
+        self.screen.x.saturating_sub(self.gutter_width())
+    }
+
+    /// The gutter marker for row `y`, if any
+    fn line_sign(&self, y: usize) -> Option<Sign> {
+        if self.rows[y].changed {
+            Some(Sign::Changed)
+        } else {
+            None
+        }
+    }
+
+    /// Draw the gutter for row `y`.
+    fn draw_gutter(&self, buf: &mut Vec<u8>, y: usize) {
+        // A narrow screen cuts the gutter short
+        let mut width = self.gutter_width();
+
+        if self.settings.change_marks && width > 0 {
+            match self.line_sign(y) {
+                Some(sign) => buf.extend(format!("\x1b[{}m{}\x1b[0m",
+                                                 sign.color(&self.settings.colors),
+                                                 sign.symbol()).as_bytes()),
+                None => buf.push(b' '),
+            }
+
+            width -= 1;
+        }
+
+        let cursor_y = self.cursor.y;
+        let number = match self.settings.line_numbers {
+            LineNumbers::Off => None,
+            LineNumbers::Relative if y != cursor_y =>
+                Some(if y > cursor_y { y - cursor_y } else { cursor_y - y }),
+            _ => Some(y + 1),
+        };
+
+        if let Some(number) = number {
+            let number = format!("{:>width$}",
+                                 number,
+                                 width = self.rows.len().to_string().len());
+            let number = &number[..cmp::min(number.len(), width)];
+
+            if !number.is_empty() {
+                buf.extend(format!("\x1b[2m{}\x1b[0m", number).as_bytes()); // Faint
+                width -= number.len();
+            }
+        }
+
+        if width > 0 {
+            buf.push(b' ');
+        }
+    }
 
     fn row_needs_rehighlight(&self, index: usize) -> bool {
         if let None = self.syntax {
@@ -313,7 +1125,7 @@ impl Editor {
     }
 
     fn update_row(&mut self, index: usize, text: String) {
-        self.rows[index].update(text);
+        self.rows[index].update(text, self.settings.tab_width);
         self.update_row_highlight(index);
     }
 
@@ -345,12 +1157,99 @@ impl Editor {
         }
     }
 
+    /// Pick up the new terminal size after a SIGWINCH.
+    fn handle_resize(&mut self) -> io::Result<()> {
+        if !WINDOW_RESIZED.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        self.update_screen_size()
+    }
+
+    /// Fit the text area to the terminal and the status rows shown.
+    fn update_screen_size(&mut self) -> io::Result<()> {
+        self.screen = get_screen_size(self.status_rows())?;
+        self.full_redraw = true;
+
+        // Keep the cursor on screen
+        let cursor = self.cursor;
+        self.scroll_to(cursor);
+
+        Ok(())
+    }
+
     pub fn refresh_screen(&mut self) -> io::Result<()> {
+        self.handle_resize()?;
+
         let mut buf: Vec<u8> = vec![];
 
+        buf.append(&mut self.pending_output);
         buf.extend(b"\x1b[?25l"); // Hide cursor
+
+        if self.full_redraw {
+            buf.extend(b"\x1b[2J"); // Clear the whole screen
+            self.full_redraw = false;
+        }
+
         buf.extend(b"\x1b[H");    // Go home
 
+        if self.settings.soft_wrap {
+            self.clamp_wrap_offset();
+            self.draw_wrapped_rows(&mut buf);
+        } else {
+            self.draw_rows(&mut buf);
+        }
+
+        if self.settings.status_bar {
+            self.draw_status_bar(&mut buf);
+        }
+
+        // Message line:
+        buf.extend(b"\x1b[0K"); // CSI 0 K = Erase from cursor to EOL
+
+        if let &Some(ref status_msg) = &self.status_msg {
+            buf.extend(format!("{:<.width$}",
+                               status_msg.text,
+                               width = self.screen.x).as_bytes());
+        }
+
+        buf.extend(b"\x1b[?25h"); // Make cursor visible again
+
+        let visual_cursor = self.visual_cursor_position();
+
+        buf.extend(b"\x1b[");
+        buf.extend(format!("{};{}H", visual_cursor.y + 1, visual_cursor.x + 1).as_bytes());
+
+        self.write_output(&buf)
+    }
+
+    /// Draw the screen to `output` instead of stdout.
+    #[cfg(test)]
+    pub fn set_output(&mut self, output: Box<io::Write>) {
+        self.output = Some(output);
+    }
+
+    /// Write `bytes` to the terminal, or wherever the screen is drawn.
+    fn write_output(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut stdout_lock;
+
+        let write: &mut io::Write = match self.output {
+            Some(ref mut output) => output,
+            None => {
+                stdout_lock = stdout.lock();
+                &mut stdout_lock
+            },
+        };
+
+        write.write_all(bytes)?;
+        write.flush()
+    }
+
+    /// Draw the visible part of each row on its own screen line.
+    fn draw_rows(&self, buf: &mut Vec<u8>) {
+        let text_width = self.text_width();
+
         for y in 0..self.screen.y {
             let row_index = self.offset.y + y;
 
@@ -359,45 +1258,147 @@ impl Editor {
                 continue;
             }
 
-            let row = &self.rows[row_index];
+            let marks = self.row_marks(row_index);
 
-            let trimmed_row: String = row.render.chars()
-                .skip(self.offset.x)
-                .take(self.screen.x)
-                .collect();
+            self.draw_gutter(buf, row_index);
+            draw_row(buf, &self.rows[row_index],
+                     self.offset.x, text_width, &self.settings.colors,
+                     &marks);
+            buf.extend(b"\x1b[0K\r\n");
+        }
+    }
 
-            if let Some(ref highlight) = row.highlight {
-                let mut current_color = None;
+    /// Draw the rows broken across as many screen lines as they need.
+    fn draw_wrapped_rows(&self, buf: &mut Vec<u8>) {
+        let text_width = self.text_width();
+        let gutter_width = self.gutter_width();
 
-                let trimmed_highlight = highlight.highlight.iter()
-                    .skip(self.offset.x)
-                    .take(self.screen.x);
+        let mut row_index = self.offset.y;
+        let mut lines = self.wrap_lines(row_index);
+        let mut line = cmp::min(self.wrap_offset, lines.len() - 1);
+        let mut marks = vec![];
 
-                for (ch, hl) in trimmed_row.chars().zip(trimmed_highlight) {
-                    let color = hl.color();
-                    let color_changed = match current_color {
-                        None => true,
-                        Some(c) if c != color => true,
-                        _ => false,
-                    };
+        if row_index < self.rows.len() {
+            marks = self.row_marks(row_index);
+        }
 
-                    if color_changed {
-                        buf.extend(format!("\x1b[{}m", color).as_bytes());
-                        current_color = Some(color);
-                    }
+        for _ in 0..self.screen.y {
+            if row_index >= self.rows.len() {
+                buf.extend(b"~\x1b[0K\r\n"); // CSI 0 K = Erase from cursor to EOL
+                continue;
+            }
 
-                    buf.extend(ch.encode_utf8().as_slice());
-                }
-                buf.extend(b"\x1b[0m");
+            // Only the first screen line of a row has a line number
+            if line == 0 {
+                self.draw_gutter(buf, row_index);
             } else {
-                buf.extend(trimmed_row.as_bytes());
+                for _ in 0..gutter_width {
+                    buf.push(b' ');
+                }
             }
+
+            let (start, end) = lines[line];
+
+            draw_row(buf, &self.rows[row_index],
+                     start, cmp::min(end - start, text_width), &self.settings.colors,
+                     &marks);
             buf.extend(b"\x1b[0K\r\n");
+
+            line += 1;
+
+            if line == lines.len() {
+                row_index += 1;
+                line = 0;
+
+                if row_index < self.rows.len() {
+                    lines = self.wrap_lines(row_index);
+                    marks = self.row_marks(row_index);
+                }
+            }
+        }
+    }
+
+    /// Ranges of `render` on row `y` to draw with a mark
+    fn row_marks(&self, y: usize) -> Vec<(usize, usize, Mark)> {
+        let row = &self.rows[y];
+        let tab_width = self.settings.tab_width;
+        let mut marks = vec![];
+
+        if let Some((start, end)) = self.search_match {
+            if start.y == y {
+                marks.push((row.render_index(start.x, tab_width),
+                            row.render_index(end, tab_width),
+                            Mark::CurrentMatch));
+            }
+        }
+
+        if let Some(ref query) = self.search_query {
+            for m in query.find_all(&row.text) {
+                if m.start < m.end {
+                    marks.push((row.render_index(m.start, tab_width),
+                                row.render_index(m.end, tab_width),
+                                Mark::Match));
+                }
+            }
+        }
+
+        if let Some((start, end)) = self.region() {
+            if start.y <= y && y <= end.y && start != end {
+                let from = if y == start.y {
+                    row.render_index(start.x, tab_width)
+                } else {
+                    0
+                };
+                let to = if y == end.y {
+                    row.render_index(end.x, tab_width)
+                } else {
+                    row.render.chars().count()
+                };
+
+                marks.push((from, to, Mark::Region));
+            }
+        }
+
+        marks
+    }
+
+    /// The number of the current match among the matches of `query` in
+    /// the whole buffer, if there is a current match, and their total.
+    /// The matches are only looked for again when the query or the text
+    /// has changed.
+    fn match_count(&mut self, query: &Rc<Query>) -> (Option<usize>, usize) {
+        let fresh = match self.match_cache {
+            Some((ref cached, version, _)) =>
+                &**cached as *const Query == &**query as *const Query
+                    && version == self.text_version,
+            None => false,
+        };
+
+        if !fresh {
+            let mut matches = vec![];
+
+            for (y, row) in self.rows.iter().enumerate() {
+                for m in query.find_all(&row.text) {
+                    matches.push((y, m.start));
+                }
+            }
+
+            self.match_cache = Some((query.clone(), self.text_version, matches));
         }
 
-        // Create two lines for status.
+        let matches = &self.match_cache.as_ref().unwrap().2;
+        let current = self.search_match.map(|(start, _)| {
+            match matches.binary_search(&(start.y, start.x)) {
+                Ok(i) => i + 1,
+                Err(i) => i,
+            }
+        });
+
+        (current, matches.len())
+    }
 
-        // First line:
+    /// Draw the inverse video bar with the file name and position.
+    fn draw_status_bar(&self, buf: &mut Vec<u8>) {
         buf.extend(b"\x1b[0K"); // CSI 0 K = Erase from cursor to EOL
         buf.extend(b"\x1b[7m"); // CSI 7 m = Use inverse video
         let cursor_fix = self.fixup(self.cursor);
@@ -416,90 +1417,323 @@ impl Editor {
             cursor_fix.y + 1,
             cursor_fix.x,
             self.rows.len(),
-            "");
+            if self.is_dirty() { "(modified)" } else { "" });
+
+        let right = format!(
+            "{}{} ",
+            self.format.line_ending.name(),
+            if self.format.bom { " BOM" } else { "" });
 
-        buf.extend(format!("{:<width$}",
+        let left_width = self.screen.x.saturating_sub(right.len());
+
+        buf.extend(format!("{:<width$.width$}{:.rwidth$}",
                            left,
-                           width = self.screen.x).as_bytes());
+                           right,
+                           width = left_width,
+                           rwidth = self.screen.x - left_width).as_bytes());
 
         buf.extend(b"\x1b[0m\r\n"); // Reset char attributes
+    }
 
-        // Second line:
-        buf.extend(b"\x1b[0K"); // CSI 0 K = Erase from cursor to EOL
+    /// Act on a key press or paste. Returns false when the editor
+    /// should quit.
+    pub fn handle_input(&mut self, input: Input) -> bool {
+        match input {
+            Input::Key(key) => self.handle_keypress(key),
+            Input::Paste(text) => {
+                self.pending_keys.clear();
+                self.quit_pending = false;
+                self.last_command = None;
+                self.paste_text(&text);
+                true
+            },
+        }
+    }
 
-        if let &Some(ref status_msg) = &self.status_msg {
-            buf.extend(format!("{:<.width$}",
-                               status_msg.text,
-                               width = self.screen.x).as_bytes());
+    fn handle_keypress(&mut self, key: read_key::Key) -> bool {
+        self.pending_keys.push(key);
+
+        let lookup = self.keymap.lookup(Context::Editor, &self.pending_keys);
+
+        if lookup == Lookup::Prefix {
+            let msg = format!("{}-", keymap::keys_name(&self.pending_keys));
+            self.set_status_message(msg);
+            return true;
         }
 
-        buf.extend(b"\x1b[?25h"); // Make cursor visible again
+        let keys = mem::replace(&mut self.pending_keys, vec![]);
 
-        let visual_cursor = self.visual_cursor_position();
+        if keys.len() > 1 {
+            self.set_status_message("".to_owned());
+        }
 
-        buf.extend(b"\x1b[");
-        buf.extend(format!("{};{}H", visual_cursor.y + 1, visual_cursor.x + 1).as_bytes());
+        let quit_pending = self.quit_pending;
+        self.quit_pending = false;
 
-        let stdout = io::stdout();
-        let write: &mut io::Write = &mut stdout.lock();
+        let version = self.history.version();
+
+        match lookup {
+            Lookup::Command(Command::Quit) => {
+                if !self.is_dirty() || quit_pending {
+                    return false;
+                }
 
-        write.write_all(&buf)?;
-        write.flush()?;
+                self.quit_pending = true;
+                self.set_status_message(
+                    "Warning: file has unsaved changes. \
+                     Press Ctrl-Q again to quit without saving.".to_owned());
+            },
+            Lookup::Command(command) => {
+                if is_cursor_move(command) {
+                    let shifted = keys.len() == 1 && keys[0].mods & read_key::SHIFT != 0;
+                    self.update_shift_selection(shifted);
+                }
 
-        Ok(())
+                self.run_command(command);
+
+                // The region commands keep the mark on the lines they changed
+                if self.history.version() != version && !keeps_mark(command) {
+                    self.clear_mark();
+                }
+
+                self.last_command = Some(command);
+            },
+            _ => {
+                match keys[0].typed_char() {
+                    Some(ch) if keys.len() == 1 => {
+                        self.clear_mark();
+                        self.insert_char(ch);
+                    },
+                    _ => {
+                        let msg = format!("{} is undefined", keymap::keys_name(&keys));
+                        self.set_status_message(msg);
+                    },
+                }
+
+                self.last_command = None;
+            },
+        }
+
+        true
+    }
+
+    /// Start a selection when moving with Shift held, or end one that
+    /// was started that way when moving without it.
+    fn update_shift_selection(&mut self, shifted: bool) {
+        if shifted {
+            if self.mark.is_none() {
+                self.mark = Some(self.fixup(self.cursor));
+                self.shift_selecting = true;
+            }
+        } else if self.shift_selecting {
+            self.clear_mark();
+        }
     }
 
-    pub fn handle_keypress(&mut self, key: read_key::Key) -> bool {
-        use read_key::Key::*;
-        use read_key::key_codes::*;
+    fn run_command(&mut self, command: Command) {
+        use keymap::Command::*;
+
+        match command {
+            Save => self.save(false),
+            SaveAs => self.save(true),
+            ToggleLineEnding => self.cycle_line_ending(),
+            ShowSettings => self.show_settings(),
+            Undo => self.undo(),
+            Redo => self.redo(),
+            Find => {
+                self.history.seal();
+
+                if let Err(e) = self.find() {
+                    self.set_status_message(
+                        format!("Error: {}", e));
+                }
+            },
+            Replace | ReplaceRegex => {
+                self.history.seal();
 
-        match key {
-            Char(CTRL_C) => (),
-            Char(CTRL_Q) => return false,
-            Char(CTRL_S) =>
-                if let Err(e) = self.save() {
+                if let Err(e) = self.replace(command == ReplaceRegex) {
                     self.set_status_message(
                         format!("Error: {}", e));
-                } else {
-                    self.set_status_message("Saved file".to_owned());
-                },
-            Char(CTRL_F) => {
-                if let Err(e) = self.find() {
+                }
+            },
+            Refresh => (),                  // Refresh screen as side effect
+            Newline => self.insert_newline(),
+            InsertTab if self.mark.is_some() => self.indent_lines(),
+            InsertTab => self.insert_tab(),
+            DeleteBackward => self.backspace(),
+            DeleteForward => self.delete_forward(),
+            DeleteWordBackward => self.delete_word_backward(),
+            DeleteWordForward => self.delete_word_forward(),
+            MoveUp | MoveDown | MoveLeft | MoveRight | PageUp | PageDown
+                | LineStart | LineEnd | WordLeft | WordRight =>
+                    self.handle_cursor_move(command),
+            SetMark => self.set_mark(),
+            Cut => self.cut(),
+            Copy => self.copy(),
+            Paste => self.paste(),
+            YankPop => self.yank_pop(),
+            Indent => self.indent_lines(),
+            Unindent => self.unindent_lines(),
+            ToggleComment => self.toggle_comment(),
+            PipeRegion => {
+                self.history.seal();
+
+                if let Err(e) = self.pipe_region() {
                     self.set_status_message(
                         format!("Error: {}", e));
                 }
             },
-            Char(ENTER) | Char(b'\n') =>
-                self.insert_newline(),
-            Char(BACKSPACE) | Char(CTRL_H) =>
-                self.backspace(),
-            PageUp | PageDown | ArrowUp | ArrowDown
-                | ArrowLeft | ArrowRight =>
-                    self.handle_cursor_move_keypress(key),
-            Char(CTRL_L) => (),                         // Refresh screen as side effect
-            Char(ch) => self.insert_char(ch as char),
-            _ => (),                                    // Unknown. Do nothing.
+            Upcase => self.change_case(true),
+            Downcase => self.change_case(false),
+            // Handled by handle_keypress
+            Quit => (),
+            // Only bound in the search prompt
+            SearchNext | SearchPrevious | HistoryPrevious | HistoryNext
+                | ToggleRegex | ToggleIgnoreCase | ToggleSmartCase
+                | ToggleWholeWord | ToggleWrap | Accept | Cancel => (),
         }
-
-        true
     }
 
-    fn handle_cursor_move_keypress(&mut self, key: read_key::Key) {
-        use read_key::Key::*;
+    fn handle_cursor_move(&mut self, command: Command) {
+        use keymap::Command::*;
 
-        let delta = match key {
-            ArrowUp => delta(0, -1),
-            ArrowDown => delta(0, 1),
-            ArrowLeft => delta(-1, 0),
-            ArrowRight => delta(1, 0),
+        self.history.seal();
+
+        let delta = match command {
+            WordLeft => {
+                let target = self.word_left(self.cursor);
+                self.move_cursor_to(target);
+                return;
+            },
+            WordRight => {
+                let target = self.word_right(self.cursor);
+                self.move_cursor_to(target);
+                return;
+            },
+            MoveUp | MoveDown | PageUp | PageDown if self.settings.soft_wrap => {
+                let screen_y = self.screen.y as isize;
+                let count = match command {
+                    MoveUp => -1,
+                    MoveDown => 1,
+                    PageUp => -screen_y,
+                    _ => screen_y,
+                };
+
+                self.move_by_screen_lines(count);
+                return;
+            },
+            MoveUp => delta(0, -1),
+            MoveDown => delta(0, 1),
+            MoveLeft => delta(-1, 0),
+            MoveRight => delta(1, 0),
             PageUp => delta(0, -(self.screen.y as isize)),
             PageDown => delta(0, self.screen.y as isize),
+            LineStart => {
+                let target = self.smart_home();
+                self.move_cursor_to(target);
+                return;
+            },
+            LineEnd => {
+                let y = self.cursor.y;
+                let x = self.rows.get(y).map_or(0, |row| row.char_count());
+                self.move_cursor_to(pos(x, y));
+                return;
+            },
             _ => return,
         };
 
         self.move_cursor_by(delta);
     }
 
+    /// Where Home goes: the first non-blank character of the line, or
+    /// the start of the line if the cursor is already there.
+    fn smart_home(&self) -> Pos {
+        let Pos {x, y} = self.fixup(self.cursor);
+
+        let indent = self.rows.get(y).map_or(0, |row| {
+            row.text.chars()
+                .take_while(|ch| ch.is_whitespace())
+                .count()
+        });
+
+        if x == indent {
+            pos(0, y)
+        } else {
+            pos(indent, y)
+        }
+    }
+
+    /// Start of the word before `from`, crossing to the end of the
+    /// previous line if `from` is at the start of a line.
+    fn word_left(&self, from: Pos) -> Pos {
+        let Pos {x, y} = self.fixup(from);
+
+        let chars: Vec<char> = match self.rows.get(y) {
+            Some(row) => row.text.chars().collect(),
+            None => return from,
+        };
+
+        if x == 0 {
+            return if y == 0 {
+                from
+            } else {
+                pos(self.rows[y - 1].char_count(), y - 1)
+            };
+        }
+
+        // Step by grapheme, going by the base char of each, so that a
+        // combining mark doesn't end a word
+        let mut x = x;
+
+        while x > 0 {
+            let start = unicode::prev_boundary_in(&chars, x);
+            if unicode::is_word_char(chars[start]) {
+                break;
+            }
+            x = start;
+        }
+        while x > 0 {
+            let start = unicode::prev_boundary_in(&chars, x);
+            if !unicode::is_word_char(chars[start]) {
+                break;
+            }
+            x = start;
+        }
+
+        pos(x, y)
+    }
+
+    /// End of the word after `from`, crossing to the start of the next
+    /// line if `from` is at the end of a line.
+    fn word_right(&self, from: Pos) -> Pos {
+        let Pos {x, y} = self.fixup(from);
+
+        let chars: Vec<char> = match self.rows.get(y) {
+            Some(row) => row.text.chars().collect(),
+            None => return from,
+        };
+
+        if x == chars.len() {
+            return if y + 1 < self.rows.len() {
+                pos(0, y + 1)
+            } else {
+                from
+            };
+        }
+
+        // Step by grapheme as in `word_left`
+        let mut x = x;
+
+        while x < chars.len() && !unicode::is_word_char(chars[x]) {
+            x = unicode::next_boundary_in(&chars, x);
+        }
+        while x < chars.len() && unicode::is_word_char(chars[x]) {
+            x = unicode::next_boundary_in(&chars, x);
+        }
+
+        pos(x, y)
+    }
+
     fn move_cursor_to(&mut self, mut pos: Pos) {
         if pos.y >= self.rows.len() {
             if self.rows.len() == 0 {
@@ -512,33 +1746,155 @@ impl Editor {
         self.scroll_to(pos);
 
         self.cursor = pos;
+        self.wrap_goal = None;
     }
 
-    /// Constrain the `x` of the cursor to its line
+    /// Constrain the `x` of the cursor to its line, and to the start of
+    /// a grapheme cluster
     fn fixup(&self, pos: Pos) -> Pos {
         let Pos {mut x, y} = pos;
 
-        match self.rows.get(y) {
-            Some(row) if x > row.text.len() =>
-                x = row.text.len(),
-            _ => (),
+        if let Some(row) = self.rows.get(y) {
+            x = unicode::floor_boundary(&row.text,
+                                        cmp::min(x, row.char_count()));
         }
 
         Pos {x: x, y: y}
     }
 
+    /// Adjust the offset so that `pos` is on screen
     fn scroll_to(&mut self, pos: Pos) {
-        let Pos {y, ..} = pos;
+        let Pos {x, y} = self.fixup(pos);
+
+        if self.settings.soft_wrap {
+            self.scroll_to_wrapped(Pos {x: x, y: y});
+            return;
+        }
 
         if y < self.offset.y {
             self.offset.y = y;
         } else if y >= self.offset.y + self.screen.y {
             self.offset.y = y - self.screen.y + 1;
         }
+
+        // Scroll horizontally by rendered column so that tabs and wide
+        // characters are accounted for.
+        let (start, end) = match self.rows.get(y) {
+            Some(row) => {
+                let tab_width = self.settings.tab_width;
+                let start = row.render_x(x, tab_width);
+                let end = match row.text.chars().nth(x) {
+                    Some(ch) => cmp::max(next_column(start, ch, tab_width), start + 1),
+                    None => start + 1,
+                };
+
+                (start, end)
+            },
+            None => (0, 1),
+        };
+
+        let text_width = self.text_width();
+
+        if start < self.offset.x {
+            self.offset.x = start;
+        } else if end > self.offset.x + text_width {
+            self.offset.x = end.saturating_sub(text_width);
+        }
     }
 
-    fn move_cursor_by(&mut self, delta: Delta) {
-        let Delta {dx, dy} = delta;
+    /// Adjust `offset.y` and `wrap_offset` so that `pos` is on screen
+    /// with soft wrap.
+    fn scroll_to_wrapped(&mut self, pos: Pos) {
+        self.clamp_wrap_offset();
+
+        let col = match self.rows.get(pos.y) {
+            Some(row) => row.render_x(pos.x, self.settings.tab_width),
+            None => 0,
+        };
+
+        let target = (pos.y, wrap_line_of(&self.wrap_lines(pos.y), col));
+        let top = (self.offset.y, self.wrap_offset);
+
+        self.offset.x = 0;
+
+        if target < top {
+            self.offset.y = target.0;
+            self.wrap_offset = target.1;
+            return;
+        }
+
+        // Walk back from the target until the top is reached or the
+        // screen is full. The top becomes wherever the walk stopped.
+        let mut line = target;
+
+        for _ in 1..self.screen.y {
+            if line == top {
+                return;
+            }
+
+            match self.prev_screen_line(line) {
+                Some(prev) => line = prev,
+                None => break,
+            }
+        }
+
+        self.offset.y = line.0;
+        self.wrap_offset = line.1;
+    }
+
+    /// Keep `wrap_offset` within the top row, which may have been wrapped
+    /// into fewer lines since it was set.
+    fn clamp_wrap_offset(&mut self) {
+        let last = self.wrap_lines(self.offset.y).len() - 1;
+        self.wrap_offset = cmp::min(self.wrap_offset, last);
+    }
+
+    /// Move the cursor `count` screen lines up or down with soft wrap,
+    /// keeping to the same column where the lines are long enough.
+    fn move_by_screen_lines(&mut self, count: isize) {
+        let tab_width = self.settings.tab_width;
+        let cursor = self.fixup(self.cursor);
+
+        let col = match self.rows.get(cursor.y) {
+            Some(row) => row.render_x(cursor.x, tab_width),
+            None => 0,
+        };
+
+        let lines = self.wrap_lines(cursor.y);
+        let mut line = (cursor.y, wrap_line_of(&lines, col));
+        let goal = self.wrap_goal.unwrap_or(col - lines[line.1].0);
+
+        for _ in 0..count.abs() {
+            let next = if count > 0 {
+                self.next_screen_line(line)
+            } else {
+                self.prev_screen_line(line)
+            };
+
+            match next {
+                Some(next) => line = next,
+                None => break,
+            }
+        }
+
+        let (y, k) = line;
+        let lines = self.wrap_lines(y);
+        let (start, end) = lines[k];
+
+        // Stop short of the column where the next screen line starts
+        let last = if k + 1 < lines.len() { end - 1 } else { end };
+
+        let x = match self.rows.get(y) {
+            Some(row) => row.char_at_column(cmp::min(start + goal, last), tab_width),
+            None => 0,
+        };
+
+        self.move_cursor_to(pos(x, y));
+        self.wrap_goal = Some(goal);
+    }
+
+    fn move_cursor_by(&mut self, delta: Delta) {
+        let Delta {dx, dy} = delta;
 
         // Don't pre-fixup `x` unless the user explicitly requests it
         // by moving laterally.
@@ -548,8 +1904,22 @@ impl Editor {
             self.cursor
         };
 
-        let new_curs = pos(uclamp(x as isize + dx),
-                           uclamp(y as isize + dy));
+        // Step over whole grapheme clusters when moving laterally.
+        let mut new_x = x;
+
+        if let Some(row) = self.rows.get(y) {
+            for _ in 0..dx.abs() {
+                new_x = if dx > 0 {
+                    unicode::next_boundary(&row.text, new_x)
+                } else {
+                    unicode::prev_boundary(&row.text, new_x)
+                };
+            }
+        } else {
+            new_x = uclamp(x as isize + dx);
+        }
+
+        let new_curs = pos(new_x, uclamp(y as isize + dy));
 
         // Again, only post-fixup if this was explicitly requested.
         let new_curs = if dx != 0 {
@@ -568,55 +1938,199 @@ impl Editor {
         });
     }
 
+    /// Show `msg` unless something is on the status line already, so
+    /// that the first of the problems found while starting up is the one
+    /// seen.
+    fn set_first_status_message(&mut self, msg: String) {
+        if self.status_msg.is_none() {
+            self.set_status_message(msg);
+        }
+    }
+
     fn ensure_line_exists(&mut self) {
         while self.rows.len() <= self.cursor.y {
             self.rows.push(Row::new());
         }
     }
 
-    fn insert_char(&mut self, ch: char) {
-        assert!(ch != '\n', "insert_char called with newline");
-
-        self.ensure_line_exists();
+    /// Insert `text`, which may contain newlines, at `at` without
+    /// recording it in the history. Returns the position just past the
+    /// inserted text.
+    fn buffer_insert(&mut self, at: Pos, text: &str) -> Pos {
+        let Pos {x, y} = at;
+        self.text_version += 1;
 
-        let cursor_fixup = self.fixup(self.cursor);
+        while self.rows.len() <= y {
+            self.rows.push(Row::new());
+        }
 
-        let mut row_text;
+        let row_left: String;
+        let row_right: String;
+        let row_crlf: bool;
 
         {
-            let row: &Row = &self.rows[self.cursor.y];
-            row_text = row.text.clone();
+            let row = &self.rows[y];
+            let split = row.byte_index(x);
+            row_left = (&row.text[..split]).to_owned();
+            row_right = (&row.text[split..]).to_owned();
+            row_crlf = row.crlf;
         }
-        row_text.insert(cursor_fixup.x, ch);
 
-        let row_index = self.cursor.y;
-        self.update_row(row_index, row_text);
+        // The original line ending stays at the end of the last line;
+        // the newly inserted ones follow the buffer's style.
+        let new_crlf = match self.format.line_ending {
+            LineEnding::Lf => false,
+            LineEnding::CrLf => true,
+            LineEnding::Mixed => row_crlf,
+        };
 
-        self.cursor.x += 1;
-    }
+        let mut lines: Vec<String> = text.split('\n')
+            .map(|s| s.to_owned())
+            .collect();
 
-    fn insert_newline(&mut self) {
-        self.ensure_line_exists();
+        lines[0] = row_left + &lines[0];
+        lines.last_mut().unwrap().push_str(&row_right);
 
-        let Pos {x, y} = self.fixup(self.cursor);
+        // Build all of the new rows before touching the highlighting so
+        // that a large insertion only rehighlights once.
+        let tail = self.rows.split_off(y + 1);
+        let mut lines = lines.into_iter();
+
+        let tab_width = self.settings.tab_width;
+        self.rows[y].update(lines.next().unwrap(), tab_width);
+        self.rows[y].changed = true;
+
+        for line in lines {
+            let mut row = Row::new();
+            row.update(line, tab_width);
+            row.crlf = new_crlf;
+            row.changed = true;
+            self.rows.push(row);
+        }
+
+        if self.rows.len() > y + 1 {
+            self.rows[y].crlf = new_crlf;
+            self.rows.last_mut().unwrap().crlf = row_crlf;
+        }
 
+        self.rows.extend(tail);
+        self.update_row_highlight(y);
+
+        undo::end_of(at, text)
+    }
+
+    /// Delete the text between `start` and `end` without recording it in
+    /// the history. Returns the deleted text.
+    fn buffer_delete(&mut self, start: Pos, end: Pos) -> String {
+        assert!((start.y, start.x) <= (end.y, end.x), "delete range reversed");
+        self.text_version += 1;
+
+        let mut deleted = String::new();
         let row_left: String;
         let row_right: String;
+        let row_crlf: bool;
 
         {
-            let row: &Row = &mut self.rows[y];
-            row_left = (&row.text[..x]).to_owned();
-            row_right = (&row.text[x..]).to_owned();
+            let first = &self.rows[start.y];
+            let last = &self.rows[end.y];
+            let split_left = first.byte_index(start.x);
+            let split_right = last.byte_index(end.x);
+
+            row_left = (&first.text[..split_left]).to_owned();
+            row_right = (&last.text[split_right..]).to_owned();
+            row_crlf = last.crlf;
+
+            if start.y == end.y {
+                deleted.push_str(&first.text[split_left..split_right]);
+            } else {
+                deleted.push_str(&first.text[split_left..]);
+
+                for row in &self.rows[start.y + 1..end.y] {
+                    deleted.push('\n');
+                    deleted.push_str(&row.text);
+                }
+
+                deleted.push('\n');
+                deleted.push_str(&last.text[..split_right]);
+            }
+        }
+
+        self.rows.drain(start.y + 1..end.y + 1);
+        self.rows[start.y].crlf = row_crlf;
+        self.rows[start.y].changed = true;
+        self.update_row(start.y, row_left + &row_right);
+
+        deleted
+    }
+
+    /// Insert `text` at `at`, recording the edit and moving the cursor
+    /// to the end of the inserted text.
+    fn insert_text(&mut self, at: Pos, text: &str) {
+        let cursor_before = self.cursor;
+        let end = self.buffer_insert(at, text);
+
+        self.move_cursor_to(end);
+
+        let edit = undo::Edit {
+            kind: undo::EditKind::Insert,
+            at: at,
+            text: text.to_owned(),
+        };
+
+        self.history.record(edit, cursor_before, end);
+    }
+
+    /// Delete the text between `start` and `end`, recording the edit and
+    /// moving the cursor to `start`.
+    fn delete_text(&mut self, start: Pos, end: Pos) {
+        let cursor_before = self.cursor;
+        let text = self.buffer_delete(start, end);
+
+        self.move_cursor_to(start);
+
+        let edit = undo::Edit {
+            kind: undo::EditKind::Delete,
+            at: start,
+            text: text,
+        };
+
+        self.history.record(edit, cursor_before, start);
+    }
+
+    fn insert_char(&mut self, ch: char) {
+        assert!(ch != '\n', "insert_char called with newline");
+
+        self.ensure_line_exists();
+
+        let at = self.fixup(self.cursor);
+        let mut text = String::new();
+        text.push(ch);
+
+        self.insert_text(at, &text);
+    }
+
+    /// Insert a tab, or spaces up to the next indent stop with soft tabs.
+    fn insert_tab(&mut self) {
+        if !self.settings.soft_tabs {
+            self.insert_char('\t');
+            return;
         }
 
-        self.update_row(y, row_left);
+        self.ensure_line_exists();
+
+        let at = self.fixup(self.cursor);
+        let indent_width = self.settings.indent_width();
+        let col = self.rows[at.y].render_x(at.x, self.settings.tab_width);
+        let spaces: String = (0..indent_width - col % indent_width).map(|_| ' ').collect();
+
+        self.insert_text(at, &spaces);
+    }
 
-        let new_row = Row::new();
-        let new_row_y = y + 1;
+    fn insert_newline(&mut self) {
+        self.ensure_line_exists();
 
-        self.rows.insert(new_row_y, new_row);
-        self.update_row(new_row_y, row_right);
-        self.move_cursor_to(Pos {x: 0, y: new_row_y});
+        let at = self.fixup(self.cursor);
+        self.insert_text(at, "\n");
     }
 
     fn backspace(&mut self) {
@@ -628,137 +2142,1448 @@ impl Editor {
             if y == 0 {
                 return;
             }
-            let new_y = y - 1;
-            let new_x = self.rows[new_y].text.len();
 
-            let lower_row = self.rows.remove(y);
+            let new_x = self.rows[y - 1].char_count();
+            self.delete_text(pos(new_x, y - 1), pos(x, y));
+        } else {
+            let new_x = unicode::prev_boundary(&self.rows[y].text, x);
+            self.delete_text(pos(new_x, y), pos(x, y));
+        }
+    }
 
-            let mut new_row_text = self.rows[new_y].text.clone();
-            new_row_text.push_str(&lower_row.text);
+    /// Delete the grapheme under the cursor, joining the next line onto
+    /// this one at the end of a line.
+    fn delete_forward(&mut self) {
+        self.ensure_line_exists();
 
-            self.update_row(new_y, new_row_text);
+        let Pos {x, y} = self.fixup(self.cursor);
 
-            self.move_cursor_to(Pos {x: new_x, y: new_y});
+        if x == self.rows[y].char_count() {
+            if y + 1 < self.rows.len() {
+                self.delete_text(pos(x, y), pos(0, y + 1));
+            }
         } else {
-            let mut row_text = self.rows[y].text.clone();
-            row_text.remove(x - 1);
-            self.update_row(y, row_text);
-            self.move_cursor_to(Pos {x: x - 1, y: y});
+            let end_x = unicode::next_boundary(&self.rows[y].text, x);
+            self.delete_text(pos(x, y), pos(end_x, y));
         }
     }
 
-    fn find(&mut self) -> io::Result<()> {
-        use read_key::Key::*;
-        use read_key::key_codes::*;
+    fn delete_word_backward(&mut self) {
+        let end = self.fixup(self.cursor);
+        let start = self.word_left(end);
 
-        let mut query = String::new();
-        let mut direction = 0isize;
+        if start != end {
+            self.delete_text(start, end);
+        }
+    }
 
-        let saved_cursor = self.cursor;
-        let saved_offset = self.offset;
+    fn delete_word_forward(&mut self) {
+        let start = self.fixup(self.cursor);
+        let end = self.word_right(start);
 
-        let stdin = io::stdin();
+        if start != end {
+            self.delete_text(start, end);
+        }
+    }
 
-        loop {
-            self.set_status_message(format!(
-                "Search: {} (Use ESC/Arrows/Enter)",
-                query));
-            self.refresh_screen()?;
+    fn clear_mark(&mut self) {
+        self.mark = None;
+        self.shift_selecting = false;
+    }
 
-            let key = {
-                if let Some(k) = read_key::read_escape(&mut stdin.lock())?.interpret() {
-                    k
-                } else {
-                    continue;
-                }
-            };
+    /// Set the mark at the cursor, or drop it if it is already set.
+    fn set_mark(&mut self) {
+        // Edits made with the mark set must start a new step, so that
+        // handle_keypress notices them
+        self.history.seal();
 
-            match key {
-                Char(CTRL_H) | Char(BACKSPACE) => {
-                    let _ = query.pop();
-                },
-                Esc | Char(ENTER) | Char(b'\n') => {
-                    if let Esc = key {
-                        self.cursor = saved_cursor;
-                        self.offset = saved_offset;
-                    }
+        if self.mark.is_some() {
+            self.clear_mark();
+            self.set_status_message("Mark cleared".to_owned());
+        } else {
+            self.mark = Some(self.fixup(self.cursor));
+            self.shift_selecting = false;
+            self.set_status_message("Mark set".to_owned());
+        }
+    }
 
-                    self.set_status_message("".to_owned());
-                    break;
-                },
-                ArrowRight | ArrowDown => {
-                    direction = 1;
-                },
-                ArrowLeft | ArrowUp => {
-                    direction = -1;
-                },
-                Char(ch) if isprint(ch) => {
-                    query.push(ch as char);
-                },
-                _ => (),
-            }
+    /// The start and end of the text between the mark and the cursor,
+    /// if the mark is set
+    fn region(&self) -> Option<(Pos, Pos)> {
+        let mark = match self.mark {
+            Some(mark) if !self.rows.is_empty() => self.fixup(mark),
+            _ => return None,
+        };
+        let cursor = self.fixup(self.cursor);
 
-            let tmp_cursor = self.cursor;
-            let found = if direction >= 0 {
-                if direction > 0 {
-                    self.move_cursor_by(delta(1, 0));
-                }
-                self.search_forward(&query)
-            } else {
-                self.search_backward(&query)
-            };
+        if (mark.y, mark.x) <= (cursor.y, cursor.x) {
+            Some((mark, cursor))
+        } else {
+            Some((cursor, mark))
+        }
+    }
+
+    /// The first and last row the region covers, or the cursor row if
+    /// the mark is not set. A region ending at the start of a row leaves
+    /// that row out.
+    fn region_rows(&self) -> (usize, usize) {
+        match self.region() {
+            Some((start, end)) if end.y > start.y && end.x == 0 => (start.y, end.y - 1),
+            Some((start, end)) => (start.y, end.y),
+            None => (self.cursor.y, self.cursor.y),
+        }
+    }
+
+    /// The text between `start` and `end`, with a newline between rows
+    fn text_between(&self, start: Pos, end: Pos) -> String {
+        let mut text = String::new();
 
-            if !found {
-                self.move_cursor_to(tmp_cursor);
+        for y in start.y..end.y + 1 {
+            let row = &self.rows[y];
+            let from = if y == start.y { row.byte_index(start.x) } else { 0 };
+            let to = if y == end.y { row.byte_index(end.x) } else { row.text.len() };
+
+            if y > start.y {
+                text.push('\n');
             }
+            text.push_str(&row.text[from..to]);
         }
 
-        Ok(())
+        text
     }
 
-    fn search_forward(&mut self, query: &str) -> bool {
-        loop {
-            let Pos {x, y} = self.cursor;
+    /// The region, or the cursor row and its line break if the mark is
+    /// not set
+    fn region_or_line(&self) -> Option<(Pos, Pos)> {
+        let y = self.cursor.y;
+
+        if self.mark.is_some() {
+            self.region()
+        } else if y + 1 < self.rows.len() {
+            Some((pos(0, y), pos(0, y + 1)))
+        } else if y < self.rows.len() {
+            Some((pos(0, y), pos(self.rows[y].char_count(), y)))
+        } else {
+            None
+        }
+    }
+
+    /// Cut the region, or the cursor row if the mark is not set. A run
+    /// of cuts goes into the kill ring as one kill.
+    fn cut(&mut self) {
+        let (start, end) = match self.region_or_line() {
+            Some(range) => range,
+            None => return,
+        };
 
-            if let Some(match_idx) = find_char(&self.rows[y].text, query, x) {
-                self.move_cursor_to(pos(match_idx, y));
-                return true;
+        let text = self.text_between(start, end);
+
+        if self.last_command == Some(Command::Cut) && self.kill_appendable {
+            self.kill_ring.append(&text);
+        } else {
+            // Cutting nothing leaves no kill to add the next cut to
+            self.kill_appendable = !text.is_empty();
+
+            if self.kill_appendable {
+                self.kill_ring.push(text);
             }
+        }
+
+        self.export_kill();
+        self.clear_mark();
+
+        if start != end {
+            self.history.begin_group();
+            self.delete_text(start, end);
+            self.history.end_group();
+        }
+    }
 
-            if y + 1 == self.rows.len() {
-                return false;
+    /// Copy the region, or the cursor row if the mark is not set.
+    fn copy(&mut self) {
+        let (start, end) = match self.region_or_line() {
+            Some(range) => range,
+            None => return,
+        };
+
+        let what = if self.mark.is_some() { "region" } else { "line" };
+        let text = self.text_between(start, end);
+
+        if !text.is_empty() {
+            self.kill_ring.push(text);
+        }
+
+        self.clear_mark();
+        self.set_status_message(format!("Copied the {}", what));
+        self.export_kill();
+    }
+
+    /// Put the most recent kill on the system clipboard, through the
+    /// terminal if it can be, or else with a clipboard tool if there is
+    /// one.
+    fn export_kill(&mut self) {
+        let text = match self.kill_ring.latest() {
+            Some(text) => text.to_owned(),
+            None => return,
+        };
+
+        if self.copies_with_osc52(&text) {
+            self.pending_output.extend(clipboard::osc52(&text));
+            return;
+        }
+
+        if !self.settings.clipboard_tools {
+            return;
+        }
+
+        if let Some(tool) = clipboard::find_tool() {
+            if let Err(e) = clipboard::copy(tool, &text) {
+                self.set_status_message(
+                    format!("Error copying to the clipboard: {}", e));
             }
+        }
+    }
+
+    /// Whether `text` is put on the clipboard through the terminal
+    fn copies_with_osc52(&self, text: &str) -> bool {
+        self.settings.clipboard_osc52 && text.len() <= self.settings.clipboard_osc52_limit
+    }
+
+    /// Add the contents of the system clipboard to the kill ring if they
+    /// have changed since the last kill. The terminal can't be asked for
+    /// them, so this only happens when kills go to a clipboard tool.
+    fn import_clipboard(&mut self) {
+        let osc52 = match self.kill_ring.latest() {
+            Some(text) => self.copies_with_osc52(text),
+            None => self.settings.clipboard_osc52,
+        };
+
+        if osc52 || !self.settings.clipboard_tools {
+            return;
+        }
+
+        let text = match clipboard::find_tool().map(clipboard::paste) {
+            Some(Ok(text)) => text,
+            Some(Err(ref e)) if e.kind() == io::ErrorKind::TimedOut => {
+                self.set_status_message(
+                    format!("Error pasting from the clipboard: {}", e));
+                return;
+            },
+            // Some tools fail when the clipboard is empty
+            _ => return,
+        };
 
-            self.move_cursor_to(Pos {x: 0, y: y + 1});
+        if !text.is_empty() && self.kill_ring.latest() != Some(&text[..]) {
+            self.kill_ring.push(text);
         }
     }
 
+    /// Insert the most recent kill, replacing the region.
+    fn paste(&mut self) {
+        self.yank_range = None;
+        self.import_clipboard();
 
-    fn search_backward(&mut self, query: &str) -> bool {
-        loop {
-            let Pos {x, y} = self.cursor;
+        let text = match self.kill_ring.yank().map(|text| text.to_owned()) {
+            Some(text) => text,
+            None => {
+                self.set_status_message("Nothing to paste".to_owned());
+                return;
+            },
+        };
+
+        self.ensure_line_exists();
 
-            if let Some(match_idx) = rfind_char(&self.rows[y].text, query, x) {
-                self.move_cursor_to(Pos {x: match_idx, y: y});
-                return true;
+        let region = self.region();
+        self.clear_mark();
+
+        self.history.begin_group();
+
+        if let Some((start, end)) = region {
+            if start != end {
+                self.delete_text(start, end);
             }
+        }
 
-            if y == 0 {
-                return false;
+        let at = self.fixup(self.cursor);
+        self.insert_text(at, &text);
+
+        self.history.end_group();
+
+        self.yank_range = Some((at, self.cursor));
+    }
+
+    /// Insert text pasted into the terminal as it is, replacing the
+    /// region, as one step that is undone at once.
+    fn paste_text(&mut self, text: &str) {
+        // Terminals send line breaks in a paste as CR
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+
+        self.ensure_line_exists();
+
+        let region = self.region();
+        self.clear_mark();
+
+        self.history.begin_group();
+
+        if let Some((start, end)) = region {
+            if start != end {
+                self.delete_text(start, end);
             }
+        }
+
+        if !text.is_empty() {
+            let at = self.fixup(self.cursor);
+            self.insert_text(at, &text);
+        }
 
-            let upper_line_len = self.rows[y -  1].text.chars().count();
+        self.history.end_group();
+    }
+
+    /// Replace the text just pasted with the kill before it in the ring.
+    fn yank_pop(&mut self) {
+        let (start, end) = match (self.last_command, self.yank_range) {
+            (Some(Command::Paste), Some(range))
+                | (Some(Command::YankPop), Some(range)) => range,
+            _ => {
+                self.set_status_message("The last command was not a paste".to_owned());
+                return;
+            },
+        };
+
+        let text = match self.kill_ring.rotate().map(|text| text.to_owned()) {
+            Some(text) => text,
+            None => return,
+        };
+
+        self.history.begin_group();
 
-            self.move_cursor_to(Pos {x: upper_line_len.saturating_sub(1),
-                                     y: y - 1});
+        if start != end {
+            self.delete_text(start, end);
         }
+        if !text.is_empty() {
+            self.insert_text(start, &text);
+        }
+
+        self.history.end_group();
+
+        self.yank_range = Some((start, self.cursor));
     }
-}
 
-impl Drop for Editor {
-    fn drop(&mut self) {
-        if let Err(e) = self.disable_raw_mode() {
-            println!("Warning: Couldn't disable raw mode: {}", e);
+    /// Change each row of the region, or the cursor row, as one step.
+    /// `edit` is given the text of a row and returns where to replace
+    /// how many chars with what, or `None` to leave the row alone. The
+    /// cursor and the mark stay on the same text.
+    fn edit_rows<F>(&mut self, mut edit: F)
+            where F: FnMut(&str) -> Option<(usize, usize, String)> {
+        self.ensure_line_exists();
+
+        let (first, last) = self.region_rows();
+        let mut cursor = self.fixup(self.cursor);
+        let mut mark = self.mark.map(|mark| self.fixup(mark));
+
+        self.history.begin_group();
+
+        for y in first..last + 1 {
+            let (x, remove, insert) = match edit(&self.rows[y].text) {
+                Some(change) => change,
+                None => continue,
+            };
+
+            if remove > 0 {
+                self.delete_text(pos(x, y), pos(x + remove, y));
+            }
+            if !insert.is_empty() {
+                self.insert_text(pos(x, y), &insert);
+            }
+
+            let inserted = insert.chars().count();
+            cursor = shift_pos(cursor, y, x, remove, inserted);
+            mark = mark.map(|mark| shift_pos(mark, y, x, remove, inserted));
         }
+
+        self.history.end_group();
+
+        self.move_cursor_to(cursor);
+        self.mark = mark;
+    }
+
+    /// Indent each non-blank row of the region, or the cursor row.
+    fn indent_lines(&mut self) {
+        let indent: String = if self.settings.soft_tabs {
+            (0..self.settings.indent_width()).map(|_| ' ').collect()
+        } else {
+            "\t".to_owned()
+        };
+
+        self.edit_rows(|text| if text.is_empty() {
+            None
+        } else {
+            Some((0, 0, indent.clone()))
+        });
+    }
+
+    /// Remove up to one indent from each row of the region, or the
+    /// cursor row.
+    fn unindent_lines(&mut self) {
+        let tab_width = self.settings.tab_width;
+        let indent_width = self.settings.indent_width();
+
+        self.edit_rows(|text| {
+            let mut col = 0;
+            let mut count = 0;
+
+            for ch in text.chars() {
+                if col >= indent_width || (ch != ' ' && ch != '\t') {
+                    break;
+                }
+
+                col = next_column(col, ch, tab_width);
+                count += 1;
+            }
+
+            if count == 0 {
+                None
+            } else {
+                Some((0, count, String::new()))
+            }
+        });
+    }
+
+    /// What starts a line comment in this file
+    fn comment_marker(&self) -> Option<String> {
+        self.settings.line_comment.clone()
+            .or_else(|| self.syntax.as_ref().map(|syntax| syntax.line_comment.clone()))
+    }
+
+    /// Comment out the non-blank rows of the region, or the cursor row,
+    /// or uncomment them if they all are already. Comment markers are
+    /// lined up at the smallest indent.
+    fn toggle_comment(&mut self) {
+        let marker = match self.comment_marker() {
+            Some(marker) => marker,
+            None => {
+                self.set_status_message(
+                    "No line comment is known for this file; set line_comment".to_owned());
+                return;
+            },
+        };
+
+        self.ensure_line_exists();
+
+        let (first, last) = self.region_rows();
+        let mut indent = None;
+        let mut commented = true;
+
+        for row in &self.rows[first..last + 1] {
+            let rest = row.text.trim_left();
+
+            if rest.is_empty() {
+                continue;
+            }
+
+            let width = row.char_count() - rest.chars().count();
+            indent = Some(cmp::min(indent.unwrap_or(width), width));
+            commented = commented && rest.starts_with(&marker[..]);
+        }
+
+        let indent = match indent {
+            Some(indent) => indent,
+            None => return,
+        };
+
+        let marker_len = marker.chars().count();
+        let prefix = format!("{} ", marker);
+
+        if commented {
+            self.edit_rows(|text| {
+                let rest = text.trim_left();
+
+                if rest.is_empty() {
+                    return None;
+                }
+
+                let x = text.chars().count() - rest.chars().count();
+                let remove = if rest.starts_with(&prefix[..]) {
+                    marker_len + 1
+                } else {
+                    marker_len
+                };
+
+                Some((x, remove, String::new()))
+            });
+        } else {
+            self.edit_rows(|text| if text.trim().is_empty() {
+                None
+            } else {
+                Some((indent, 0, prefix.clone()))
+            });
+        }
+    }
+
+    /// Change the region to upper or lower case, or the word after the
+    /// cursor if the mark is not set.
+    fn change_case(&mut self, upper: bool) {
+        self.ensure_line_exists();
+
+        let cursor = self.fixup(self.cursor);
+        let (start, end) = match self.region() {
+            Some(region) => region,
+            None => (cursor, self.word_right(cursor)),
+        };
+
+        if start == end {
+            return;
+        }
+
+        let text = self.text_between(start, end);
+        let changed = if upper {
+            text.to_uppercase()
+        } else {
+            text.to_lowercase()
+        };
+
+        if changed != text {
+            self.history.begin_group();
+            self.delete_text(start, end);
+            self.insert_text(start, &changed);
+            self.history.end_group();
+        }
+
+        // Keep the region around the changed text, which may have a
+        // different length
+        let new_end = undo::end_of(start, &changed);
+
+        if self.mark.is_some() && cursor == start {
+            self.mark = Some(new_end);
+            self.move_cursor_to(start);
+        } else {
+            if self.mark.is_some() {
+                self.mark = Some(start);
+            }
+            self.move_cursor_to(new_end);
+        }
+    }
+
+    /// Replace the region with the output of a shell command given the
+    /// region as input.
+    fn pipe_region(&mut self) -> io::Result<()> {
+        let (start, end) = match self.region() {
+            Some(region) => region,
+            None => {
+                self.set_status_message("The mark is not set".to_owned());
+                return Ok(());
+            },
+        };
+
+        let command = match self.prompt("Pipe region through: ", "", false)? {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+
+        let input = self.text_between(start, end);
+
+        let mut output = match shell::filter(&command, &input) {
+            Ok(output) => output,
+            Err(e) => {
+                self.set_status_message(format!("{}: {}", command, e));
+                return Ok(());
+            },
+        };
+
+        // Most commands end their output with a newline whether or not
+        // the input had one
+        if output.ends_with('\n') && !input.ends_with('\n') {
+            output.pop();
+        }
+
+        self.clear_mark();
+
+        self.history.begin_group();
+
+        if start != end {
+            self.delete_text(start, end);
+        }
+        if !output.is_empty() {
+            self.insert_text(start, &output);
+        }
+
+        self.history.end_group();
+
+        Ok(())
+    }
+
+    /// Revert the most recent step in the history.
+    fn undo(&mut self) {
+        let step = match self.history.take_undo() {
+            Some(step) => step,
+            None => {
+                self.set_status_message("Nothing to undo".to_owned());
+                return;
+            },
+        };
+
+        self.revert(&step);
+        self.move_cursor_to(step.cursor_before);
+        self.history.push_undone(step);
+        self.clear_change_marks_if_saved();
+    }
+
+    /// Undo the edits of `step` in the buffer.
+    fn revert(&mut self, step: &undo::Step) {
+        for edit in step.edits.iter().rev() {
+            match edit.kind {
+                undo::EditKind::Insert => {
+                    self.buffer_delete(edit.at, edit.end());
+                },
+                undo::EditKind::Delete => {
+                    self.buffer_insert(edit.at, &edit.text);
+                },
+            }
+        }
+    }
+
+    /// Reapply the most recently undone step.
+    fn redo(&mut self) {
+        let step = match self.history.take_redo() {
+            Some(step) => step,
+            None => {
+                self.set_status_message("Nothing to redo".to_owned());
+                return;
+            },
+        };
+
+        for edit in &step.edits {
+            match edit.kind {
+                undo::EditKind::Insert => {
+                    self.buffer_insert(edit.at, &edit.text);
+                },
+                undo::EditKind::Delete => {
+                    self.buffer_delete(edit.at, edit.end());
+                },
+            }
+        }
+
+        self.move_cursor_to(step.cursor_after);
+        self.history.push_redone(step);
+        self.clear_change_marks_if_saved();
+    }
+
+    /// Unmark the changed lines once undo or redo is back to the text
+    /// that was saved.
+    fn clear_change_marks_if_saved(&mut self) {
+        if self.history.version() == self.saved_version {
+            for row in &mut self.rows {
+                row.changed = false;
+            }
+        }
+    }
+
+    /// Block until a key the editor understands is pressed, ignoring
+    /// pastes.
+    fn read_key(&mut self) -> io::Result<read_key::Key> {
+        loop {
+            if let Input::Key(key) = self.read_input()? {
+                return Ok(key);
+            }
+        }
+    }
+
+    /// Block until a key the editor understands is pressed or text is
+    /// pasted.
+    pub fn read_input(&mut self) -> io::Result<Input> {
+        let stdin = io::stdin();
+
+        loop {
+            // Redraw as soon as the window is resized, even if no key
+            // has been pressed.
+            if WINDOW_RESIZED.load(Ordering::SeqCst) {
+                self.refresh_screen()?;
+            }
+
+            let escape = match self.escape_reader.read_escape(&mut stdin.lock()) {
+                Ok(Some(escape)) => escape,
+                Ok(None) => continue,
+                // SIGWINCH interrupted the read
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            if let read_key::Escape::InvalidUtf8(ref bytes) = escape {
+                let hex: Vec<String> = bytes.iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+
+                self.set_status_message(format!(
+                    "Ignored invalid UTF-8 input: {}", hex.join(" ")));
+                self.refresh_screen()?;
+            }
+
+            if let read_key::Escape::Paste(text) = escape {
+                return Ok(Input::Paste(text));
+            }
+
+            if let Some(k) = escape.interpret() {
+                return Ok(Input::Key(k));
+            }
+        }
+    }
+
+    /// Read a line of input on the status line. Returns `None` if the
+    /// user cancels with Esc. Enter is ignored while the input is empty
+    /// unless `allow_empty` is set.
+    fn prompt(&mut self, prompt: &str, initial: &str, allow_empty: bool)
+            -> io::Result<Option<String>> {
+        use read_key::KeyCode::*;
+        use read_key::CTRL;
+
+        let mut input = initial.to_owned();
+
+        loop {
+            self.set_status_message(format!("{}{}", prompt, input));
+            self.refresh_screen()?;
+
+            let key = match self.read_input()? {
+                Input::Key(key) => key,
+                Input::Paste(text) => {
+                    input.push_str(first_line(&text));
+                    continue;
+                },
+            };
+
+            match (key.code, key.mods) {
+                (Char('h'), CTRL) | (Backspace, _) => {
+                    let _ = input.pop();
+                },
+                (Esc, _) | (Char('c'), CTRL) => {
+                    self.set_status_message("".to_owned());
+                    return Ok(None);
+                },
+                (Enter, _) if allow_empty || !input.is_empty() => {
+                    self.set_status_message("".to_owned());
+                    return Ok(Some(input));
+                },
+                _ => if let Some(ch) = key.typed_char() {
+                    input.push(ch);
+                },
+            }
+        }
+    }
+
+    /// Ask a yes/no question on the status line.
+    fn confirm(&mut self, question: &str) -> io::Result<bool> {
+        use read_key::KeyCode::*;
+        use read_key::SHIFT;
+
+        loop {
+            self.set_status_message(question.to_owned());
+            self.refresh_screen()?;
+
+            // Ctrl and Alt combinations of the letters aren't answers.
+            // Shift is only reported by some terminals.
+            let key = self.read_key()?;
+
+            match (key.code, key.mods & !SHIFT) {
+                (Char('y'), 0) | (Char('Y'), 0) => return Ok(true),
+                (Char('n'), 0) | (Char('N'), 0) | (Esc, 0) => return Ok(false),
+                _ => (),
+            }
+        }
+    }
+
+    fn find(&mut self) -> io::Result<()> {
+        use keymap::Command::*;
+
+        let mut input = String::new();
+        let mut direction = 0isize;
+        let mut regex = false;
+        let mut error = None;
+        let mut wrapped = false;
+
+        // Position in the history, which is its length while editing a
+        // new search, and what was typed before going back in it
+        let mut history_index = self.search_history.len();
+        let mut draft = String::new();
+
+        let saved_cursor = self.cursor;
+        let saved_offset = (self.offset, self.wrap_offset);
+
+        loop {
+            let error_msg = match error {
+                Some(ref e) => format!(" [error: {}]", e),
+                None => String::new(),
+            };
+
+            let count_msg = match self.search_query.clone() {
+                Some(query) => match self.match_count(&query) {
+                    (Some(n), total) => format!(" [match {} of {}]", n, total),
+                    (None, 0) => " [no matches]".to_owned(),
+                    (None, total) => format!(" [{} matches]", total),
+                },
+                None => String::new(),
+            };
+
+            let mut flags = vec![];
+            if regex { flags.push("regex"); }
+            if self.search_options.ignore_case { flags.push("ignore case"); }
+            if self.search_options.smart_case { flags.push("smart case"); }
+            if self.search_options.whole_word { flags.push("whole word"); }
+            if !self.search_wrap { flags.push("no wrap"); }
+
+            let flags = if flags.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", flags.join(", "))
+            };
+
+            self.set_status_message(format!(
+                "Search{}: {}{}{}{} (Use ESC/Arrows/Enter, M-r/c/s/w/a: \
+                 regex/case/smart case/word/wrap)",
+                flags,
+                input,
+                count_msg,
+                if wrapped { " [search wrapped]" } else { "" },
+                error_msg));
+            self.refresh_screen()?;
+
+            // Pasted text is added to the input as if typed
+            let (key, lookup) = match self.read_input()? {
+                Input::Key(key) => (Some(key), self.keymap.lookup(Context::Search, &[key])),
+                Input::Paste(text) => {
+                    input.push_str(first_line(&text));
+                    self.search_match = None;
+                    (None, Lookup::Unbound)
+                },
+            };
+            wrapped = false;
+
+            match lookup {
+                Lookup::Command(DeleteBackward) => {
+                    let _ = input.pop();
+                    self.search_match = None;
+                },
+                Lookup::Command(command @ Cancel) | Lookup::Command(command @ Accept) => {
+                    if command == Cancel {
+                        self.cursor = saved_cursor;
+                        self.offset = saved_offset.0;
+                        self.wrap_offset = saved_offset.1;
+                    }
+
+                    self.set_status_message("".to_owned());
+                    break;
+                },
+                Lookup::Command(SearchNext) => {
+                    direction = 1;
+                },
+                Lookup::Command(SearchPrevious) => {
+                    direction = -1;
+                },
+                Lookup::Command(HistoryPrevious) => if history_index > 0 {
+                    if history_index == self.search_history.len() {
+                        draft = input.clone();
+                    }
+
+                    history_index -= 1;
+                    input = self.search_history[history_index].clone();
+                    self.search_match = None;
+                },
+                Lookup::Command(HistoryNext) => if history_index < self.search_history.len() {
+                    history_index += 1;
+
+                    input = match self.search_history.get(history_index) {
+                        Some(text) => text.clone(),
+                        None => draft.clone(),
+                    };
+                    self.search_match = None;
+                },
+                Lookup::Command(ToggleRegex) => {
+                    regex = !regex;
+                    self.search_match = None;
+                },
+                Lookup::Command(ToggleIgnoreCase) => {
+                    self.search_options.ignore_case = !self.search_options.ignore_case;
+                    self.search_match = None;
+                },
+                Lookup::Command(ToggleSmartCase) => {
+                    self.search_options.smart_case = !self.search_options.smart_case;
+                    self.search_match = None;
+                },
+                Lookup::Command(ToggleWholeWord) => {
+                    self.search_options.whole_word = !self.search_options.whole_word;
+                    self.search_match = None;
+                },
+                Lookup::Command(ToggleWrap) => {
+                    self.search_wrap = !self.search_wrap;
+                },
+                _ => if let Some(ch) = key.and_then(|key| key.typed_char()) {
+                    input.push(ch);
+                    self.search_match = None;
+                },
+            }
+
+            // Leave the cursor alone until the pattern is fixed
+            let query = match Query::new(&input, self.find_options(regex)) {
+                Ok(query) => {
+                    error = None;
+                    Rc::new(query)
+                },
+                Err(e) => {
+                    error = Some(e);
+                    self.search_query = None;
+                    continue;
+                },
+            };
+
+            // An empty pattern matches everywhere, which isn't worth showing
+            self.search_query = if input.is_empty() {
+                None
+            } else {
+                Some(query.clone())
+            };
+
+            let tmp_cursor = self.cursor;
+            let result = if direction >= 0 {
+                if direction > 0 {
+                    self.move_cursor_by(delta(1, 0));
+                }
+                self.search_forward(&query)
+            } else {
+                self.search_backward(&query)
+            };
+
+            match result {
+                SearchResult::NotFound => self.move_cursor_to(tmp_cursor),
+                SearchResult::Found => (),
+                SearchResult::Wrapped => wrapped = true,
+            }
+        }
+
+        self.search_match = None;
+        self.search_query = None;
+
+        if !input.is_empty() {
+            self.search_history.retain(|text| *text != input);
+            self.search_history.push(input);
+
+            if self.search_history.len() > SEARCH_HISTORY_SIZE {
+                self.search_history.remove(0);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The find prompt's search options, with regex mode on or off
+    fn find_options(&self, regex: bool) -> search::Options {
+        search::Options {
+            regex: regex,
+            ..self.search_options
+        }
+    }
+
+    /// Prompt for a pattern and a replacement, then step through the
+    /// matches after the cursor asking about each one, like Emacs'
+    /// `query-replace`. The replacements are undone as a single step.
+    fn replace(&mut self, regex: bool) -> io::Result<()> {
+        let kind = if regex { "Replace regex" } else { "Replace" };
+
+        let pattern = match self.prompt(&format!("{}: ", kind), "", false)? {
+            Some(pattern) => pattern,
+            None => return Ok(()),
+        };
+
+        let query = match Query::new(&pattern, self.find_options(regex)) {
+            Ok(query) => Rc::new(query),
+            Err(e) => {
+                self.set_status_message(format!("Invalid regex: {}", e));
+                return Ok(());
+            },
+        };
+
+        let prompt = format!("{} {} with: ", kind, pattern);
+        let replacement = match self.prompt(&prompt, "", true)? {
+            Some(replacement) => replacement,
+            None => return Ok(()),
+        };
+
+        self.search_query = Some(query.clone());
+
+        self.history.begin_group();
+        let result = self.replace_matches(&query, &replacement);
+        self.history.end_group();
+
+        self.search_match = None;
+        self.search_query = None;
+
+        let count = result?;
+        self.set_status_message(format!("Replaced {} occurrence{}",
+                                        count,
+                                        if count == 1 { "" } else { "s" }));
+
+        Ok(())
+    }
+
+    /// Replace the matches of `query` from the cursor on, asking about
+    /// each one until the user answers `a`. Returns how many were
+    /// replaced.
+    fn replace_matches(&mut self, query: &Query, replacement: &str)
+            -> io::Result<usize> {
+        use read_key::KeyCode::*;
+        use read_key::SHIFT;
+
+        let mut from = self.cursor;
+        // Where the last match ended, so that an empty match right there
+        // isn't replaced again
+        let mut last_end = None;
+        let mut ask = true;
+        let mut count = 0;
+
+        while let Some((y, m)) = self.next_match(query, from, last_end) {
+            self.select_match(m.clone(), y);
+
+            if ask {
+                self.set_status_message(format!(
+                    "Replace with {}? (y: yes, n: no, a: all, q: quit)",
+                    replacement));
+                self.refresh_screen()?;
+
+                // Only plain keys answer, so that a stray Ctrl or Alt
+                // combination can't replace everything
+                let key = self.read_key()?;
+
+                match (key.code, key.mods) {
+                    (Char('y'), 0) | (Char(' '), 0) => (),
+                    (Char('a'), 0) | (Char('!'), 0) | (Char('!'), SHIFT) => ask = false,
+                    (Char('n'), 0) => {
+                        from = pos(m.end, y);
+                        last_end = Some(from);
+                        continue;
+                    },
+                    (Char('q'), 0) | (Esc, 0) | (Enter, 0) => break,
+                    _ => continue,
+                }
+            }
+
+            let text = query.expand(&self.rows[y].text, &m, replacement);
+
+            if m.start < m.end {
+                self.delete_text(pos(m.start, y), pos(m.end, y));
+            }
+
+            if !text.is_empty() {
+                self.insert_text(pos(m.start, y), &text);
+            }
+
+            count += 1;
+            from = self.cursor;
+            last_end = Some(from);
+        }
+
+        Ok(count)
+    }
+
+    /// The first match of `query` at or after `from`, passing over an
+    /// empty match at `skip`.
+    fn next_match(&self, query: &Query, from: Pos, skip: Option<Pos>)
+            -> Option<(usize, search::Match)> {
+        let Pos {mut x, mut y} = from;
+
+        while y < self.rows.len() {
+            let found = if x <= self.rows[y].char_count() {
+                query.find(&self.rows[y].text, x)
+            } else {
+                None
+            };
+
+            match found {
+                Some(ref m) if m.start == m.end && Some(pos(m.start, y)) == skip =>
+                    x = m.start + 1,
+                Some(m) => return Some((y, m)),
+                None => {
+                    x = 0;
+                    y += 1;
+                },
+            }
+        }
+
+        None
+    }
+
+    /// Move the cursor to the start of `m` on row `y` and mark the match.
+    fn select_match(&mut self, m: search::Match, y: usize) {
+        self.move_cursor_to(pos(m.start, y));
+        self.search_match = Some((pos(m.start, y), m.end));
+    }
+
+    /// Move to the next match at or after the cursor, continuing from
+    /// the top of the buffer if the settings allow.
+    fn search_forward(&mut self, query: &Query) -> SearchResult {
+        let total = self.rows.len();
+
+        if total == 0 {
+            return SearchResult::NotFound;
+        }
+
+        // Below the last line, the next row is the first one
+        let (start_x, start_y, mut wrapped) = if self.cursor.y < total {
+            (self.cursor.x, self.cursor.y, false)
+        } else {
+            (0, 0, true)
+        };
+
+        if wrapped && !self.search_wrap {
+            return SearchResult::NotFound;
+        }
+
+        for i in 0..total + 1 {
+            let mut y = start_y + i;
+
+            if y >= total {
+                if !self.search_wrap {
+                    break;
+                }
+
+                wrapped = true;
+                y -= total;
+            }
+
+            let from = if i == 0 { start_x } else { 0 };
+
+            if let Some(m) = query.find(&self.rows[y].text, from) {
+                // Back on the first row, only matches before the start
+                // haven't been seen
+                if i == total && m.start >= start_x {
+                    break;
+                }
+
+                self.select_match(m, y);
+
+                return if wrapped {
+                    SearchResult::Wrapped
+                } else {
+                    SearchResult::Found
+                };
+            }
+        }
+
+        SearchResult::NotFound
+    }
+
+    /// Move to the previous match before the cursor, continuing from the
+    /// bottom of the buffer if the settings allow.
+    fn search_backward(&mut self, query: &Query) -> SearchResult {
+        let total = self.rows.len();
+
+        if total == 0 {
+            return SearchResult::NotFound;
+        }
+
+        let (start_x, start_y) = if self.cursor.y < total {
+            (self.cursor.x, self.cursor.y)
+        } else {
+            (self.rows[total - 1].char_count() + 1, total - 1)
+        };
+
+        let mut wrapped = false;
+
+        for i in 0..total + 1 {
+            let y = if i <= start_y {
+                start_y - i
+            } else if self.search_wrap {
+                wrapped = true;
+                start_y + total - i
+            } else {
+                break;
+            };
+
+            // Past the end, so that a match ending the line is found
+            let to = if i == 0 { start_x } else { self.rows[y].char_count() + 1 };
+
+            if let Some(m) = query.rfind(&self.rows[y].text, to) {
+                if i == total && m.start <= start_x {
+                    break;
+                }
+
+                self.select_match(m, y);
+
+                return if wrapped {
+                    SearchResult::Wrapped
+                } else {
+                    SearchResult::Found
+                };
+            }
+        }
+
+        SearchResult::NotFound
+    }
+}
+
+impl Drop for Editor {
+    fn drop(&mut self) {
+        if let Err(e) = self.disable_raw_mode() {
+            println!("Warning: Couldn't disable raw mode: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::path::{Path, PathBuf};
+    use std::rc::Rc;
+
+    use file_format::LineEnding;
+
+    use super::{Editor, Row, pos};
+
+    fn editor(lines: &[&str]) -> Editor {
+        let mut editor: Editor = Default::default();
+
+        for (y, line) in lines.iter().enumerate() {
+            editor.rows.push(Row::new());
+            editor.update_row(y, (*line).to_owned());
+        }
+
+        editor
+    }
+
+    fn text(editor: &Editor) -> Vec<&str> {
+        editor.rows.iter().map(|row| &row.text[..]).collect()
+    }
+
+    /// Collects what the editor draws, while the test holds on to it
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture_output(editor: &mut Editor) -> Rc<RefCell<Vec<u8>>> {
+        let output = Rc::new(RefCell::new(vec![]));
+        editor.set_output(Box::new(Output(output.clone())));
+        editor.screen = pos(20, 5);
+        output
+    }
+
+    fn sent_osc52(output: &Rc<RefCell<Vec<u8>>>) -> bool {
+        output.borrow().windows(5).any(|w| w == b"\x1b]52;")
+    }
+
+    #[test]
+    fn insert_between_multibyte_chars() {
+        let mut e = editor(&["héllo", "日本"]);
+        e.cursor = pos(2, 0);
+        e.insert_char('ü');
+        assert_eq!(text(&e), ["héüllo", "日本"]);
+        assert_eq!(e.cursor, pos(3, 0));
+
+        e.cursor = pos(1, 1);
+        e.insert_char('の');
+        assert_eq!(text(&e), ["héüllo", "日の本"]);
+        assert_eq!(e.cursor, pos(2, 1));
+    }
+
+    #[test]
+    fn insert_inside_grapheme_goes_before_it() {
+        // e followed by a combining acute accent
+        let mut e = editor(&["he\u{301}llo"]);
+        e.cursor = pos(2, 0);
+        e.insert_char('a');
+        assert_eq!(text(&e), ["hae\u{301}llo"]);
+        assert_eq!(e.cursor, pos(2, 0));
+    }
+
+    #[test]
+    fn backspace_removes_whole_grapheme() {
+        // Man, ZWJ, woman, ZWJ, girl: one family emoji
+        let mut e = editor(&["a\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}b"]);
+        e.cursor = pos(6, 0);
+        e.backspace();
+        assert_eq!(text(&e), ["ab"]);
+        assert_eq!(e.cursor, pos(1, 0));
+
+        let mut e = editor(&["ae\u{301}b"]);
+        e.cursor = pos(3, 0);
+        e.backspace();
+        assert_eq!(text(&e), ["ab"]);
+        assert_eq!(e.cursor, pos(1, 0));
+    }
+
+    #[test]
+    fn delete_forward_removes_whole_grapheme() {
+        let mut e = editor(&["ae\u{301}b", "x\u{1f468}\u{200d}\u{1f469}y"]);
+        e.cursor = pos(1, 0);
+        e.delete_forward();
+        assert_eq!(text(&e)[0], "ab");
+        assert_eq!(e.cursor, pos(1, 0));
+
+        e.cursor = pos(1, 1);
+        e.delete_forward();
+        assert_eq!(text(&e)[1], "xy");
+        assert_eq!(e.cursor, pos(1, 1));
+    }
+
+    #[test]
+    fn split_line_in_multibyte_text() {
+        let mut e = editor(&["héllo日本"]);
+        e.cursor = pos(6, 0);
+        e.insert_newline();
+        assert_eq!(text(&e), ["héllo日", "本"]);
+        assert_eq!(e.cursor, pos(0, 1));
+
+        // Inside a grapheme the split moves to its start
+        let mut e = editor(&["ae\u{301}b"]);
+        e.cursor = pos(2, 0);
+        e.insert_newline();
+        assert_eq!(text(&e), ["a", "e\u{301}b"]);
+    }
+
+    #[test]
+    fn join_lines_with_multibyte_text() {
+        let mut e = editor(&["héllo日", "本e\u{301}"]);
+        e.cursor = pos(0, 1);
+        e.backspace();
+        assert_eq!(text(&e), ["héllo日本e\u{301}"]);
+        assert_eq!(e.cursor, pos(6, 0));
+
+        let mut e = editor(&["日本", "ü\u{1f468}\u{200d}\u{1f469}"]);
+        e.cursor = pos(2, 0);
+        e.delete_forward();
+        assert_eq!(text(&e), ["日本ü\u{1f468}\u{200d}\u{1f469}"]);
+        assert_eq!(e.cursor, pos(2, 0));
+    }
+
+    #[test]
+    fn delete_across_rows_with_multibyte_text() {
+        let mut e = editor(&["héllo", "日本", "wörld"]);
+        let deleted = e.buffer_delete(pos(2, 0), pos(2, 2));
+        assert_eq!(deleted, "llo\n日本\nwö");
+        assert_eq!(text(&e), ["hérld"]);
+    }
+
+    #[test]
+    fn failed_save_leaves_whitespace() {
+        let mut e = editor(&["a  ", "b\t"]);
+        e.settings.trim_trailing_whitespace = true;
+        e.file_path = Some(PathBuf::from("/nonexistent/dir/file.txt"));
+        e.save(false);
+        assert_eq!(text(&e), ["a  ", "b\t"]);
+        assert!(e.history.take_undo().is_none());
+    }
+
+    #[test]
+    fn copy_sends_osc52_on_refresh() {
+        let mut e = editor(&["hi", "there"]);
+        let output = capture_output(&mut e);
+        e.settings.clipboard_tools = false;
+        e.copy();
+        e.refresh_screen().unwrap();
+        assert!(output.borrow().starts_with(b"\x1b]52;c;aGkK\x07"));
+
+        // Only once
+        output.borrow_mut().clear();
+        e.refresh_screen().unwrap();
+        assert!(!sent_osc52(&output));
+    }
+
+    #[test]
+    fn copy_skips_osc52_over_limit_or_when_off() {
+        let mut e = editor(&["hi", "there"]);
+        let output = capture_output(&mut e);
+        e.settings.clipboard_tools = false;
+        e.settings.clipboard_osc52_limit = 2;
+        e.copy();
+        e.refresh_screen().unwrap();
+        assert!(!sent_osc52(&output));
+
+        e.settings.clipboard_osc52_limit = 100;
+        e.settings.clipboard_osc52 = false;
+        e.copy();
+        e.refresh_screen().unwrap();
+        assert!(!sent_osc52(&output));
+    }
+
+    #[test]
+    fn cycle_line_ending_back_to_mixed() {
+        let mut e = editor(&["a", "b", "c"]);
+        e.rows[0].crlf = true;
+        e.format.line_ending = LineEnding::Mixed;
+        e.saved_line_ending = LineEnding::Mixed;
+
+        e.cycle_line_ending();
+        assert_eq!(e.format.line_ending, LineEnding::Lf);
+        assert!(e.is_dirty());
+        e.cycle_line_ending();
+        assert_eq!(e.format.line_ending, LineEnding::CrLf);
+        e.cycle_line_ending();
+        assert_eq!(e.format.line_ending, LineEnding::Mixed);
+        assert!(!e.is_dirty());
+
+        let crlf: Vec<bool> = e.rows.iter().map(|row| row.crlf).collect();
+        assert_eq!(crlf, [true, false, false]);
+    }
+
+    #[test]
+    fn cycle_line_ending_without_mixed() {
+        let mut e = editor(&["a", "b"]);
+        e.cycle_line_ending();
+        assert_eq!(e.format.line_ending, LineEnding::CrLf);
+        e.cycle_line_ending();
+        assert_eq!(e.format.line_ending, LineEnding::Lf);
+        assert!(!e.is_dirty());
+    }
+
+    #[test]
+    fn word_motion_keeps_combining_marks() {
+        let e = editor(&["cafe\u{301} x\u{301}y"]);
+        assert_eq!(e.word_right(pos(0, 0)), pos(5, 0));
+        assert_eq!(e.word_right(pos(5, 0)), pos(9, 0));
+        assert_eq!(e.word_left(pos(9, 0)), pos(6, 0));
+        assert_eq!(e.word_left(pos(6, 0)), pos(0, 0));
+    }
+
+    #[test]
+    fn delete_word_forward_keeps_combining_marks() {
+        let mut e = editor(&["cafe\u{301} x"]);
+        e.cursor = pos(0, 0);
+        e.delete_word_forward();
+        assert_eq!(text(&e), [" x"]);
+    }
+
+    #[test]
+    fn open_keeps_an_earlier_error() {
+        let path = Path::new("/nonexistent/dir/file.txt");
+
+        let mut e = editor(&[]);
+        e.open(path).unwrap();
+        assert_eq!(e.status_msg.as_ref().unwrap().text, "New file");
+
+        let mut e = editor(&[]);
+        e.set_status_message("Error in config".to_owned());
+        e.open(path).unwrap();
+        assert_eq!(e.status_msg.as_ref().unwrap().text, "Error in config");
+    }
+
+    #[test]
+    fn find_toggles_survive_reapplying_settings() {
+        let mut e = editor(&[]);
+        e.search_options.whole_word = true;
+        e.search_wrap = false;
+        e.apply_settings();
+        assert!(e.search_options.whole_word);
+        assert!(!e.search_wrap);
+
+        // Until the setting itself changes
+        e.settings.search_whole_word = true;
+        e.apply_settings();
+        assert!(!e.search_options.whole_word);
+        assert!(!e.search_wrap);
     }
 }
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+use config::Settings;
+use glob;
+
+/// Properties we know how to apply. Others are ignored, as the
+/// EditorConfig spec asks.
+const SUPPORTED: &'static [&'static str] = &[
+    "indent_style",
+    "indent_size",
+    "tab_width",
+    "end_of_line",
+    "insert_final_newline",
+    "trim_trailing_whitespace",
+    "charset",
+];
+
+/// The merged properties for one file.
+#[derive(Debug, Default)]
+pub struct Properties {
+    values: HashMap<String, String>,
+    // The .editorconfig files that were read, nearest first
+    pub files: Vec<PathBuf>,
+}
+
+/// Escape glob metacharacters in a literal path
+fn escape(s: &str) -> String {
+    let mut result = String::new();
+
+    for ch in s.chars() {
+        if "*?[]{}\\".contains(ch) {
+            result.push('\\');
+        }
+        result.push(ch);
+    }
+
+    result
+}
+
+/// Whether the section `pattern` of the .editorconfig in `dir` matches
+/// `path`. Patterns containing a slash are relative to `dir`; others
+/// match a file name in any directory below it.
+fn section_matches(pattern: &str, dir: &Path, path: &Path) -> bool {
+    let dir = escape(&dir.to_string_lossy());
+    let dir = dir.trim_right_matches('/');
+
+    let pattern = if pattern.contains('/') {
+        format!("{}/{}", dir, pattern.trim_left_matches('/'))
+    } else {
+        format!("{}/**/{}", dir, pattern)
+    };
+
+    glob::matches(&pattern, &path.to_string_lossy())
+}
+
+fn parse_pair(line: &str) -> Option<(String, String)> {
+    line.find('=').map(|i| {
+        (line[..i].trim().to_lowercase(), line[i + 1..].trim().to_lowercase())
+    })
+}
+
+fn is_root(text: &str) -> bool {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with(';'))
+        .take_while(|line| !line.starts_with('['))
+        .filter_map(parse_pair)
+        .any(|(key, value)| key == "root" && value == "true")
+}
+
+impl Properties {
+    /// Merge the sections of one .editorconfig that match `path`.
+    fn merge(&mut self, text: &str, dir: &Path, path: &Path) {
+        // Lines before the first section only hold `root`
+        let mut in_section = false;
+
+        for line in text.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                in_section = section_matches(&line[1..line.len() - 1], dir, path);
+                continue;
+            }
+
+            if !in_section {
+                continue;
+            }
+
+            if let Some((key, value)) = parse_pair(line) {
+                if value == "unset" {
+                    self.values.remove(&key);
+                } else {
+                    self.values.insert(key, value);
+                }
+            }
+        }
+    }
+
+    /// Apply the properties to `settings`, returning a message for each
+    /// value that isn't supported.
+    pub fn apply(&self, settings: &mut Settings) -> Vec<String> {
+        let mut values = self.values.clone();
+
+        // Defaults that the spec derives from other properties
+        if values.get("indent_style").map_or(false, |v| v == "tab")
+                && !values.contains_key("indent_size") {
+            values.insert("indent_size".to_owned(), "tab".to_owned());
+        }
+
+        let size = values.get("indent_size").cloned();
+
+        if let Some(size) = size {
+            if size != "tab" && !values.contains_key("tab_width") {
+                values.insert("tab_width".to_owned(), size);
+            }
+        }
+
+        let mut errors = vec![];
+
+        for name in SUPPORTED {
+            if let Some(value) = values.get(*name) {
+                if let Err(msg) = settings.set(name, value) {
+                    errors.push(format!(".editorconfig: {}", msg));
+                }
+            }
+        }
+
+        errors
+    }
+}
+
+/// Find and merge the .editorconfig files that apply to `path`, from
+/// its directory up to the root or the first file with `root = true`.
+pub fn properties_for(path: &Path) -> io::Result<Properties> {
+    let path = if path.is_absolute() {
+        path.to_owned()
+    } else {
+        env::current_dir()?.join(path)
+    };
+
+    let mut found = vec![];
+    let mut dir = path.parent();
+
+    while let Some(d) = dir {
+        let file = d.join(".editorconfig");
+
+        match fs::File::open(&file) {
+            Ok(mut f) => {
+                let mut text = String::new();
+                f.read_to_string(&mut text)?;
+
+                let root = is_root(&text);
+                found.push((d.to_owned(), file, text));
+
+                if root {
+                    break;
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        dir = d.parent();
+    }
+
+    let mut properties: Properties = Default::default();
+
+    // Nearer files take precedence, so they are merged last.
+    for &(ref dir, _, ref text) in found.iter().rev() {
+        properties.merge(text, dir, &path);
+    }
+
+    properties.files = found.into_iter().map(|(_, file, _)| file).collect();
+
+    Ok(properties)
+}
@@ -0,0 +1,137 @@
+use std::io;
+
+const BOM: &'static str = "\u{feff}";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    // Each line keeps the ending it was read with
+    Mixed,
+}
+
+impl Default for LineEnding {
+    fn default() -> LineEnding {
+        LineEnding::Lf
+    }
+}
+
+impl LineEnding {
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+            LineEnding::Mixed => "Mixed",
+        }
+    }
+}
+
+/// How the buffer is laid out on disk, beyond the text of its lines.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FileFormat {
+    pub line_ending: LineEnding,
+    pub final_newline: bool,
+    pub bom: bool,
+}
+
+impl Default for FileFormat {
+    fn default() -> FileFormat {
+        FileFormat {
+            line_ending: Default::default(),
+            final_newline: true,
+            bom: false,
+        }
+    }
+}
+
+/// A line of text and whether it was terminated by CRLF.
+pub type Line = (String, bool);
+
+/// Split the contents of a file into lines, detecting its format.
+pub fn decode(bytes: Vec<u8>) -> io::Result<(FileFormat, Vec<Line>)> {
+    let mut text = String::from_utf8(bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let bom = text.starts_with(BOM);
+    if bom {
+        text.drain(..BOM.len());
+    }
+
+    let final_newline = text.is_empty() || text.ends_with('\n');
+    let mut lines: Vec<Line> = vec![];
+
+    let pieces: Vec<&str> = text.split_terminator('\n').collect();
+    let count = pieces.len();
+
+    for (i, line) in pieces.into_iter().enumerate() {
+        // A CR is only part of the line ending if a LF followed it
+        let terminated = final_newline || i + 1 < count;
+
+        if terminated && line.ends_with('\r') {
+            lines.push((line[..line.len() - 1].to_owned(), true));
+        } else {
+            lines.push((line.to_owned(), false));
+        }
+    }
+
+    let line_ending = {
+        // The last line has no ending to inspect unless the file has a
+        // final newline.
+        let terminated = if final_newline {
+            &lines[..]
+        } else {
+            &lines[..lines.len() - 1]
+        };
+
+        let crlf_count = terminated.iter().filter(|l| l.1).count();
+
+        if crlf_count == 0 {
+            LineEnding::Lf
+        } else if crlf_count == terminated.len() {
+            LineEnding::CrLf
+        } else {
+            LineEnding::Mixed
+        }
+    };
+
+    let format = FileFormat {
+        line_ending: line_ending,
+        final_newline: final_newline,
+        bom: bom,
+    };
+
+    Ok((format, lines))
+}
+
+/// Join lines back together in the given format.
+pub fn encode<'a, I>(format: FileFormat, lines: I) -> Vec<u8>
+        where I: ExactSizeIterator<Item=(&'a str, bool)> {
+    let mut result = vec![];
+
+    if format.bom {
+        result.extend(BOM.as_bytes());
+    }
+
+    let count = lines.len();
+
+    for (i, (text, crlf)) in lines.enumerate() {
+        result.extend(text.as_bytes());
+
+        if i + 1 == count && !format.final_newline {
+            break;
+        }
+
+        let crlf = match format.line_ending {
+            LineEnding::Lf => false,
+            LineEnding::CrLf => true,
+            LineEnding::Mixed => crlf,
+        };
+
+        if crlf {
+            result.push(b'\r');
+        }
+        result.push(b'\n');
+    }
+
+    result
+}
@@ -0,0 +1,204 @@
+//! Glob patterns as used by the section headers of config files and
+//! `.editorconfig`: `*` matches within a path component, `**` matches
+//! across components and `**/` matches zero or more directories, while
+//! `?`, `[abc]`, `[!a-z]`, `{foo,bar}` and `{1..10}` work as in the shell.
+
+/// Whether `pattern` matches all of `text`.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    match_here(&pattern, &text)
+}
+
+fn match_here(p: &[char], t: &[char]) -> bool {
+    match p.first() {
+        None => t.is_empty(),
+        // "**/" also matches no directories at all
+        Some(&'*') if p.starts_with(&['*', '*', '/']) && match_here(&p[3..], t) => true,
+        Some(&'*') => {
+            let (p, cross_slash) = if p.get(1) == Some(&'*') {
+                (&p[2..], true)
+            } else {
+                (&p[1..], false)
+            };
+
+            for i in 0..t.len() + 1 {
+                if match_here(p, &t[i..]) {
+                    return true;
+                }
+
+                if i < t.len() && t[i] == '/' && !cross_slash {
+                    break;
+                }
+            }
+
+            false
+        },
+        Some(&'?') => match t.first() {
+            Some(&ch) if ch != '/' => match_here(&p[1..], &t[1..]),
+            _ => false,
+        },
+        Some(&'[') => match (match_class(&p[1..]), t.first()) {
+            (Some((len, ref class)), Some(&ch)) if ch != '/' =>
+                class.matches(ch) && match_here(&p[1 + len..], &t[1..]),
+            (Some(_), _) => false,
+            (None, _) => match_literal('[', &p[1..], t),
+        },
+        Some(&'{') => match close_brace(&p[1..]) {
+            Some(len) => {
+                let body = &p[1..1 + len];
+                let rest = &p[2 + len..];
+
+                match_braces(body, rest, t)
+            },
+            None => match_literal('{', &p[1..], t),
+        },
+        Some(&'\\') if p.len() > 1 => match_literal(p[1], &p[2..], t),
+        Some(&ch) => match_literal(ch, &p[1..], t),
+    }
+}
+
+fn match_literal(ch: char, p: &[char], t: &[char]) -> bool {
+    t.first() == Some(&ch) && match_here(p, &t[1..])
+}
+
+struct Class {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+}
+
+impl Class {
+    fn matches(&self, ch: char) -> bool {
+        let found = self.ranges.iter().any(|&(lo, hi)| lo <= ch && ch <= hi);
+        found != self.negated
+    }
+}
+
+/// Parse the body of a `[...]` class. Returns the length of the body
+/// including the closing bracket, or `None` if it is never closed.
+fn match_class(p: &[char]) -> Option<(usize, Class)> {
+    let mut class = Class {negated: false, ranges: vec![]};
+    let mut i = 0;
+
+    if p.first() == Some(&'!') {
+        class.negated = true;
+        i += 1;
+    }
+
+    // A ']' right at the start is part of the class
+    let start = i;
+
+    while i < p.len() {
+        let ch = p[i];
+
+        if ch == ']' && i > start {
+            return Some((i + 1, class));
+        }
+
+        if i + 2 < p.len() && p[i + 1] == '-' && p[i + 2] != ']' {
+            class.ranges.push((ch, p[i + 2]));
+            i += 3;
+        } else {
+            class.ranges.push((ch, ch));
+            i += 1;
+        }
+    }
+
+    None
+}
+
+/// Length of the body of a `{...}` group, up to its matching brace
+fn close_brace(p: &[char]) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = 0;
+
+    while i < p.len() {
+        match p[i] {
+            '\\' => i += 1,
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => (),
+        }
+
+        i += 1;
+    }
+
+    None
+}
+
+/// Split a brace body at its top-level commas
+fn split_alternatives(body: &[char]) -> Vec<&[char]> {
+    let mut result = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    let mut i = 0;
+
+    while i < body.len() {
+        match body[i] {
+            '\\' => i += 1,
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                result.push(&body[start..i]);
+                start = i + 1;
+            },
+            _ => (),
+        }
+
+        i += 1;
+    }
+
+    result.push(&body[start..]);
+    result
+}
+
+/// Parse a `{n1..n2}` body
+fn parse_range(body: &[char]) -> Option<(i64, i64)> {
+    let body: String = body.iter().cloned().collect();
+    let mut parts = body.splitn(2, "..");
+
+    match (parts.next(), parts.next()) {
+        (Some(lo), Some(hi)) => match (lo.parse(), hi.parse()) {
+            (Ok(lo), Ok(hi)) => Some((lo, hi)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn match_braces(body: &[char], rest: &[char], t: &[char]) -> bool {
+    if let Some((lo, hi)) = parse_range(body) {
+        // The number is the whole run of digits at the start of the text
+        let sign = if t.first() == Some(&'-') { 1 } else { 0 };
+        let digits = t[sign..].iter().take_while(|ch| ch.is_digit(10)).count();
+
+        if digits == 0 {
+            return false;
+        }
+
+        let number: String = t[..sign + digits].iter().cloned().collect();
+
+        return match number.parse::<i64>() {
+            Ok(n) => lo <= n && n <= hi && match_here(rest, &t[sign + digits..]),
+            Err(_) => false,
+        };
+    }
+
+    let alternatives = split_alternatives(body);
+
+    if alternatives.len() == 1 {
+        // Not a real group, so the braces are literal
+        let mut literal = body.to_vec();
+        literal.push('}');
+        literal.extend_from_slice(rest);
+        return match_literal('{', &literal, t);
+    }
+
+    alternatives.iter().any(|alt| {
+        let mut pattern = alt.to_vec();
+        pattern.extend_from_slice(rest);
+        match_here(&pattern, t)
+    })
+}
@@ -0,0 +1,498 @@
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::{fs, io};
+
+use read_key::{Key, KeyCode, SHIFT, ALT, CTRL};
+
+/// Something a key can be bound to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+    Quit,
+    Save,
+    SaveAs,
+    ToggleLineEnding,
+    ShowSettings,
+    Undo,
+    Redo,
+    Find,
+    Replace,
+    ReplaceRegex,
+    Refresh,
+    Newline,
+    InsertTab,
+    DeleteBackward,
+    DeleteForward,
+    DeleteWordBackward,
+    DeleteWordForward,
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    PageUp,
+    PageDown,
+    LineStart,
+    LineEnd,
+    WordLeft,
+    WordRight,
+    SetMark,
+    Cut,
+    Copy,
+    Paste,
+    YankPop,
+    Indent,
+    Unindent,
+    ToggleComment,
+    PipeRegion,
+    Upcase,
+    Downcase,
+    // Only meaningful in the search prompt
+    SearchNext,
+    SearchPrevious,
+    HistoryPrevious,
+    HistoryNext,
+    ToggleRegex,
+    ToggleIgnoreCase,
+    ToggleSmartCase,
+    ToggleWholeWord,
+    ToggleWrap,
+    Accept,
+    Cancel,
+}
+
+const COMMAND_NAMES: &'static [(Command, &'static str)] = &[
+    (Command::Quit,               "quit"),
+    (Command::Save,               "save"),
+    (Command::SaveAs,             "save-as"),
+    (Command::ToggleLineEnding,   "toggle-line-ending"),
+    (Command::ShowSettings,       "show-settings"),
+    (Command::Undo,               "undo"),
+    (Command::Redo,               "redo"),
+    (Command::Find,               "find"),
+    (Command::Replace,            "replace"),
+    (Command::ReplaceRegex,       "replace-regex"),
+    (Command::Refresh,            "refresh"),
+    (Command::Newline,            "newline"),
+    (Command::InsertTab,          "insert-tab"),
+    (Command::DeleteBackward,     "delete-backward"),
+    (Command::DeleteForward,      "delete-forward"),
+    (Command::DeleteWordBackward, "delete-word-backward"),
+    (Command::DeleteWordForward,  "delete-word-forward"),
+    (Command::MoveUp,             "move-up"),
+    (Command::MoveDown,           "move-down"),
+    (Command::MoveLeft,           "move-left"),
+    (Command::MoveRight,          "move-right"),
+    (Command::PageUp,             "page-up"),
+    (Command::PageDown,           "page-down"),
+    (Command::LineStart,          "line-start"),
+    (Command::LineEnd,            "line-end"),
+    (Command::WordLeft,           "word-left"),
+    (Command::WordRight,          "word-right"),
+    (Command::SetMark,            "set-mark"),
+    (Command::Cut,                "cut"),
+    (Command::Copy,               "copy"),
+    (Command::Paste,              "paste"),
+    (Command::YankPop,            "yank-pop"),
+    (Command::Indent,             "indent"),
+    (Command::Unindent,           "unindent"),
+    (Command::ToggleComment,      "toggle-comment"),
+    (Command::PipeRegion,         "pipe-region"),
+    (Command::Upcase,             "upcase"),
+    (Command::Downcase,           "downcase"),
+    (Command::SearchNext,         "search-next"),
+    (Command::SearchPrevious,     "search-previous"),
+    (Command::HistoryPrevious,    "history-previous"),
+    (Command::HistoryNext,        "history-next"),
+    (Command::ToggleRegex,        "toggle-regex"),
+    (Command::ToggleIgnoreCase,   "toggle-ignore-case"),
+    (Command::ToggleSmartCase,    "toggle-smart-case"),
+    (Command::ToggleWholeWord,    "toggle-whole-word"),
+    (Command::ToggleWrap,         "toggle-wrap"),
+    (Command::Accept,             "accept"),
+    (Command::Cancel,             "cancel"),
+];
+
+impl Command {
+    fn from_name(name: &str) -> Option<Command> {
+        COMMAND_NAMES.iter()
+            .find(|&&(_, n)| n == name)
+            .map(|&(command, _)| command)
+    }
+}
+
+/// Where a key is pressed. Each context has its own bindings.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Context {
+    Editor,
+    Search,
+}
+
+impl Context {
+    fn accepts(self, command: Command) -> bool {
+        use self::Command::*;
+
+        let search_only = match command {
+            SearchNext | SearchPrevious | HistoryPrevious | HistoryNext
+                | ToggleRegex | ToggleIgnoreCase | ToggleSmartCase
+                | ToggleWholeWord | ToggleWrap | Accept | Cancel => true,
+            _ => false,
+        };
+
+        match self {
+            Context::Editor => !search_only,
+            Context::Search => search_only || command == DeleteBackward,
+        }
+    }
+}
+
+const DEFAULT_BINDINGS: &'static [(Context, &'static str, Command)] = &[
+    (Context::Editor, "C-q",         Command::Quit),
+    (Context::Editor, "C-s",         Command::Save),
+    (Context::Editor, "C-o",         Command::SaveAs),
+    (Context::Editor, "C-t",         Command::ToggleLineEnding),
+    (Context::Editor, "F2",          Command::ShowSettings),
+    (Context::Editor, "C-z",         Command::Undo),
+    (Context::Editor, "C-y",         Command::Redo),
+    (Context::Editor, "C-f",         Command::Find),
+    (Context::Editor, "C-r",         Command::Replace),
+    (Context::Editor, "M-r",         Command::ReplaceRegex),
+    (Context::Editor, "C-l",         Command::Refresh),
+    (Context::Editor, "Enter",       Command::Newline),
+    (Context::Editor, "C-j",         Command::Newline),
+    (Context::Editor, "Tab",         Command::InsertTab),
+    (Context::Editor, "Backspace",   Command::DeleteBackward),
+    (Context::Editor, "C-h",         Command::DeleteBackward),
+    (Context::Editor, "Del",         Command::DeleteForward),
+    (Context::Editor, "C-w",         Command::DeleteWordBackward),
+    (Context::Editor, "M-Backspace", Command::DeleteWordBackward),
+    (Context::Editor, "C-Del",       Command::DeleteWordForward),
+    (Context::Editor, "M-Del",       Command::DeleteWordForward),
+    (Context::Editor, "M-d",         Command::DeleteWordForward),
+    (Context::Editor, "Up",          Command::MoveUp),
+    (Context::Editor, "Down",        Command::MoveDown),
+    (Context::Editor, "Left",        Command::MoveLeft),
+    (Context::Editor, "Right",       Command::MoveRight),
+    (Context::Editor, "PageUp",      Command::PageUp),
+    (Context::Editor, "PageDown",    Command::PageDown),
+    (Context::Editor, "Home",        Command::LineStart),
+    (Context::Editor, "End",         Command::LineEnd),
+    (Context::Editor, "C-Left",      Command::WordLeft),
+    (Context::Editor, "M-Left",      Command::WordLeft),
+    (Context::Editor, "C-Right",     Command::WordRight),
+    (Context::Editor, "M-Right",     Command::WordRight),
+    (Context::Editor, "C-Space",     Command::SetMark),
+    (Context::Editor, "C-x",         Command::Cut),
+    (Context::Editor, "C-k",         Command::Cut),
+    (Context::Editor, "C-c",         Command::Copy),
+    (Context::Editor, "C-v",         Command::Paste),
+    (Context::Editor, "M-y",         Command::YankPop),
+    (Context::Editor, "S-Tab",       Command::Unindent),
+    (Context::Editor, "M-;",         Command::ToggleComment),
+    (Context::Editor, "M-|",         Command::PipeRegion),
+    (Context::Editor, "M-u",         Command::Upcase),
+    (Context::Editor, "M-l",         Command::Downcase),
+
+    (Context::Search, "Backspace",   Command::DeleteBackward),
+    (Context::Search, "C-h",         Command::DeleteBackward),
+    (Context::Search, "Esc",         Command::Cancel),
+    (Context::Search, "Enter",       Command::Accept),
+    (Context::Search, "Right",       Command::SearchNext),
+    (Context::Search, "C-s",         Command::SearchNext),
+    (Context::Search, "Left",        Command::SearchPrevious),
+    (Context::Search, "C-r",         Command::SearchPrevious),
+    (Context::Search, "Up",          Command::HistoryPrevious),
+    (Context::Search, "Down",        Command::HistoryNext),
+    (Context::Search, "M-r",         Command::ToggleRegex),
+    (Context::Search, "M-c",         Command::ToggleIgnoreCase),
+    (Context::Search, "M-s",         Command::ToggleSmartCase),
+    (Context::Search, "M-w",         Command::ToggleWholeWord),
+    (Context::Search, "M-a",         Command::ToggleWrap),
+];
+
+const KEY_NAMES: &'static [(KeyCode, &'static str)] = &[
+    (KeyCode::Enter,      "Enter"),
+    (KeyCode::Tab,        "Tab"),
+    (KeyCode::Backspace,  "Backspace"),
+    (KeyCode::Esc,        "Esc"),
+    (KeyCode::Insert,     "Insert"),
+    (KeyCode::Del,        "Del"),
+    (KeyCode::PageUp,     "PageUp"),
+    (KeyCode::PageDown,   "PageDown"),
+    (KeyCode::ArrowUp,    "Up"),
+    (KeyCode::ArrowDown,  "Down"),
+    (KeyCode::ArrowRight, "Right"),
+    (KeyCode::ArrowLeft,  "Left"),
+    (KeyCode::Home,       "Home"),
+    (KeyCode::End,        "End"),
+    (KeyCode::Char(' '),  "Space"),
+];
+
+/// Parse a single chord such as `C-x`, `M-Left` or `F5`.
+pub fn parse_key(s: &str) -> Option<Key> {
+    use read_key::KeyCode::*;
+
+    let mut mods = 0;
+    let mut rest = s;
+
+    // A trailing "-" is the key itself, as in "C--"
+    while rest.len() > 2 {
+        let modifier = if rest.starts_with("C-") {
+            CTRL
+        } else if rest.starts_with("M-") {
+            ALT
+        } else if rest.starts_with("S-") {
+            SHIFT
+        } else {
+            break;
+        };
+
+        mods |= modifier;
+        rest = &rest[2..];
+    }
+
+    let named = KEY_NAMES.iter()
+        .find(|&&(_, name)| name == rest)
+        .map(|&(code, _)| code);
+
+    let code = if let Some(code) = named {
+        code
+    } else if rest.starts_with('F') && rest.len() > 1 {
+        match rest[1..].parse::<u8>() {
+            Ok(n) if (1...12).contains(n) => F(n),
+            _ => return None,
+        }
+    } else {
+        let mut chars = rest.chars();
+
+        match (chars.next(), chars.next()) {
+            (Some(ch), None) => Char(ch),
+            _ => return None,
+        }
+    };
+
+    // Describe Ctrl combinations the way the terminal sends them, so
+    // that C-i is Tab and C-[ is Esc.
+    if let Char(ch) = code {
+        if mods & CTRL != 0 {
+            let ch = ch.to_ascii_lowercase();
+
+            let control = match ch {
+                'a'...'z' => Some(ch as u8 - b'a' + 1),
+                ' ' | '@' => Some(0),
+                '['...'_' => Some(ch as u8 - b'@'),
+                '?' => Some(0x7f),
+                _ => None,
+            };
+
+            if let Some(byte) = control {
+                return Some(Key::from_char(byte as char).with(mods & !CTRL));
+            }
+        }
+    }
+
+    Some(Key::new(code, mods))
+}
+
+/// Parse a space-separated sequence of chords such as `C-x C-s`.
+pub fn parse_keys(s: &str) -> Option<Vec<Key>> {
+    let keys: Option<Vec<Key>> = s.split_whitespace()
+        .map(parse_key)
+        .collect();
+
+    keys.and_then(|keys| if keys.is_empty() { None } else { Some(keys) })
+}
+
+pub fn key_name(key: Key) -> String {
+    let mut name = String::new();
+
+    if key.mods & CTRL != 0 {
+        name.push_str("C-");
+    }
+    if key.mods & ALT != 0 {
+        name.push_str("M-");
+    }
+    if key.mods & SHIFT != 0 {
+        name.push_str("S-");
+    }
+
+    let named = KEY_NAMES.iter()
+        .find(|&&(code, _)| code == key.code)
+        .map(|&(_, name)| name);
+
+    match (named, key.code) {
+        (Some(s), _) => name.push_str(s),
+        (None, KeyCode::F(n)) => name.push_str(&format!("F{}", n)),
+        (None, KeyCode::Char(ch)) => name.push(ch),
+        (None, _) => name.push('?'),
+    }
+
+    name
+}
+
+pub fn keys_name(keys: &[Key]) -> String {
+    let names: Vec<String> = keys.iter()
+        .map(|&key| key_name(key))
+        .collect();
+
+    names.join(" ")
+}
+
+/// Result of looking up the keys pressed so far.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Lookup {
+    Command(Command),
+    // More keys are needed to complete a binding
+    Prefix,
+    Unbound,
+}
+
+#[derive(Debug, Default)]
+struct Bindings {
+    map: HashMap<Vec<Key>, Command>,
+}
+
+impl Bindings {
+    /// Bind `keys` to `command`, or unbind them if it is `None`. A
+    /// sequence cannot be both bound and the prefix of another binding,
+    /// so any binding that conflicts with the new one is dropped.
+    fn bind(&mut self, keys: Vec<Key>, command: Option<Command>) {
+        let conflicts: Vec<Vec<Key>> = self.map.keys()
+            .filter(|k| k.starts_with(&keys) || keys.starts_with(k))
+            .cloned()
+            .collect();
+
+        for k in conflicts {
+            self.map.remove(&k);
+        }
+
+        if let Some(command) = command {
+            self.map.insert(keys, command);
+        }
+    }
+
+    fn lookup(&self, keys: &[Key]) -> Lookup {
+        if let Some(&command) = self.map.get(keys) {
+            return Lookup::Command(command);
+        }
+
+        if self.map.keys().any(|k| k.len() > keys.len() && k.starts_with(keys)) {
+            Lookup::Prefix
+        } else {
+            Lookup::Unbound
+        }
+    }
+}
+
+pub struct Keymap {
+    editor: Bindings,
+    search: Bindings,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        let mut keymap = Keymap {
+            editor: Default::default(),
+            search: Default::default(),
+        };
+
+        for &(context, keys, command) in DEFAULT_BINDINGS {
+            let keys = parse_keys(keys).expect("invalid default binding");
+            keymap.bindings_mut(context).bind(keys, Some(command));
+        }
+
+        keymap
+    }
+}
+
+impl Keymap {
+    fn bindings_mut(&mut self, context: Context) -> &mut Bindings {
+        match context {
+            Context::Editor => &mut self.editor,
+            Context::Search => &mut self.search,
+        }
+    }
+
+    pub fn lookup(&self, context: Context, keys: &[Key]) -> Lookup {
+        let bindings = match context {
+            Context::Editor => &self.editor,
+            Context::Search => &self.search,
+        };
+
+        match bindings.lookup(keys) {
+            // Fall back to the unshifted key, so that S-Up moves up
+            // unless it is bound to something else.
+            Lookup::Unbound if keys.len() == 1 && keys[0].mods & SHIFT != 0 => {
+                let key = Key::new(keys[0].code, keys[0].mods & !SHIFT);
+                bindings.lookup(&[key])
+            },
+            result => result,
+        }
+    }
+
+    /// Apply the bindings in `path` on top of the current ones. Each
+    /// line has the form `KEYS = COMMAND`, and a `[search]` line starts
+    /// the bindings for the search prompt. Returns a message for each
+    /// line that could not be used.
+    pub fn read_file(&mut self, path: &Path) -> io::Result<Vec<String>> {
+        let mut text = String::new();
+        fs::File::open(path)?.read_to_string(&mut text)?;
+
+        let file_name = path.file_name()
+            .map_or("keys".into(), |name| name.to_string_lossy());
+
+        let mut context = Context::Editor;
+        let mut errors = vec![];
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let result = match line {
+                "[editor]" => {
+                    context = Context::Editor;
+                    Ok(())
+                },
+                "[search]" => {
+                    context = Context::Search;
+                    Ok(())
+                },
+                _ => self.read_binding(context, line),
+            };
+
+            if let Err(msg) = result {
+                errors.push(format!("{}:{}: {}", file_name, i + 1, msg));
+            }
+        }
+
+        Ok(errors)
+    }
+
+    fn read_binding(&mut self, context: Context, line: &str) -> Result<(), String> {
+        // Split at the last '=' so that "C-= = undo" works
+        let (keys, command) = match line.rfind('=') {
+            Some(i) if i > 0 => (line[..i].trim(), line[i + 1..].trim()),
+            _ => return Err(format!("expected `KEYS = COMMAND`: {}", line)),
+        };
+
+        let keys = match parse_keys(keys) {
+            Some(keys) => keys,
+            None => return Err(format!("unknown key `{}`", keys)),
+        };
+
+        let command = match command {
+            "none" => None,
+            _ => match Command::from_name(command) {
+                Some(c) if context.accepts(c) => Some(c),
+                Some(_) => return Err(format!("`{}` can't be used here", command)),
+                None => return Err(format!("unknown command `{}`", command)),
+            },
+        };
+
+        self.bindings_mut(context).bind(keys, command);
+        Ok(())
+    }
+}
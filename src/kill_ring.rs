@@ -0,0 +1,58 @@
+/// How many kills are remembered
+const KILL_RING_SIZE: usize = 60;
+
+/// Text that has been cut or copied, for pasting back later.
+#[derive(Debug, Default)]
+pub struct KillRing {
+    // Oldest first
+    kills: Vec<String>,
+    // How many kills back from the most recent the last paste was
+    yank_index: usize,
+}
+
+impl KillRing {
+    /// Add a kill, forgetting the oldest one if the ring is full.
+    pub fn push(&mut self, text: String) {
+        if self.kills.len() == KILL_RING_SIZE {
+            self.kills.remove(0);
+        }
+
+        self.kills.push(text);
+        self.yank_index = 0;
+    }
+
+    /// Add `text` to the end of the most recent kill, so that a run of
+    /// cuts is pasted back as one.
+    pub fn append(&mut self, text: &str) {
+        if let Some(last) = self.kills.last_mut() {
+            last.push_str(text);
+            return;
+        }
+
+        self.push(text.to_owned());
+    }
+
+    /// The most recent kill, without starting a paste
+    pub fn latest(&self) -> Option<&str> {
+        self.kills.last().map(|kill| &kill[..])
+    }
+
+    /// The most recent kill
+    pub fn yank(&mut self) -> Option<&str> {
+        self.yank_index = 0;
+        self.kills.last().map(|kill| &kill[..])
+    }
+
+    /// The kill before the one last yanked, going back round to the most
+    /// recent after the oldest.
+    pub fn rotate(&mut self) -> Option<&str> {
+        if self.kills.is_empty() {
+            return None;
+        }
+
+        self.yank_index = (self.yank_index + 1) % self.kills.len();
+
+        let index = self.kills.len() - 1 - self.yank_index;
+        Some(&self.kills[index])
+    }
+}
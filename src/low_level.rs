@@ -1,5 +1,6 @@
 use std::io;
 use std::mem::zeroed;
+use std::ptr;
 use libc::*;
 
 pub type Fd = c_int;
@@ -21,3 +22,21 @@ pub fn set_termios(fd: Fd, optional_actions: c_int, termios_: &termios) -> Resul
 
     Ok(())
 }
+
+/// Install `handler` for `signum`. Blocking reads are interrupted rather
+/// than restarted when the signal arrives.
+pub fn set_signal_handler(signum: c_int, handler: extern "C" fn(c_int)) -> Result<(), io::Error> {
+    let mut action: sigaction = unsafe { zeroed() };
+    action.sa_sigaction = handler as sighandler_t;
+    action.sa_flags = 0;
+
+    if unsafe { sigemptyset(&mut action.sa_mask) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if unsafe { sigaction(signum, &action, ptr::null_mut()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
@@ -1,4 +1,5 @@
 #![feature(question_mark,
+           mpsc_recv_timeout,
            range_contains,
            inclusive_range_syntax,
            unicode,
@@ -8,14 +9,26 @@
 
 extern crate libc;
 
+mod atomic_write;
+mod clipboard;
+mod config;
 mod editor;
+mod editorconfig;
+mod file_format;
+mod glob;
+mod keymap;
+mod kill_ring;
 mod low_level;
 mod read_key;
+mod regex;
+mod search;
+mod shell;
 mod syntax;
+mod undo;
+mod unicode;
 
 use std::path::Path;
-use std::{io, env, process};
-use std::borrow::Borrow;
+use std::{env, process};
 
 use editor::Editor;
 
@@ -24,42 +37,43 @@ fn usage() {
         .nth(0)
         .unwrap_or("kilo_rust".to_owned());
 
-    println!("Usage: {} FILENAME", prog_name);
+    println!("Usage: {} [--backup] [FILENAME]", prog_name);
 }
 
 fn main() {
-    let syntax_db = syntax::make_syntax_db();
+    let mut make_backups = false;
+    let mut file_name = None;
 
-    let file_name =
-        if let Some(file_name) = env::args().nth(1) {
-            file_name
-        } else {
-            usage();
-            process::exit(1);
-        };
-
-    let file_path = Path::new(&file_name);
-    let syntax = file_path.extension()
-        .map(|s| s.to_string_lossy())
-        .and_then(|s| syntax_db.get(s.borrow(): &str));
+    for arg in env::args().skip(1) {
+        match &arg[..] {
+            "-b" | "--backup" => make_backups = true,
+            _ if file_name.is_none() && !arg.starts_with('-') =>
+                file_name = Some(arg),
+            _ => {
+                usage();
+                process::exit(1);
+            },
+        }
+    }
 
-    let stdin = io::stdin();
     let mut editor = Editor::new().unwrap();
+    editor.set_make_backups(make_backups);
 
     editor.enable_raw_mode()
         .expect("Failed to enable raw mode");
 
-    editor.open(Path::new(&file_name)).unwrap();
+    editor.load_config();
+    editor.load_keymap();
 
-    editor.set_syntax(syntax.map(|s| (**s).clone()));
+    if let Some(file_name) = file_name {
+        editor.open(Path::new(&file_name)).unwrap();
+    }
 
     loop {
         editor.refresh_screen().unwrap();
-        let opt_k = read_key::read_escape(&mut stdin.lock()).ok()
-            .and_then(|k| k.interpret());
 
-        if let Some(k) = opt_k {
-            if !editor.handle_keypress(k) {
+        if let Ok(input) = editor.read_input() {
+            if !editor.handle_input(input) {
                 break;
             }
         }
@@ -1,5 +1,6 @@
 
-use std::io;
+use std::collections::VecDeque;
+use std::{io, str};
 
 /// Poll stream once for input
 fn maybe_read_byte(stream: &mut io::Read) -> io::Result<Option<u8>> {
@@ -11,33 +12,23 @@ fn maybe_read_byte(stream: &mut io::Read) -> io::Result<Option<u8>> {
     }
 }
 
-/// Poll stream until input shows up
-fn read_byte(stream: &mut io::Read) -> io::Result<u8> {
-    loop {
-        if let Some(b) = maybe_read_byte(stream)? {
-            return Ok(b);
-        }
-    }
-}
+/// Bit set of modifier keys held with a key
+pub type Modifiers = u8;
 
-#[allow(dead_code)]
-pub mod key_codes {
-    pub const CTRL_C: u8     = 3;
-    pub const CTRL_D: u8     = 4;
-    pub const CTRL_F: u8     = 6;
-    pub const CTRL_H: u8     = 8;
-    pub const CTRL_L: u8     = 12;
-    pub const ENTER: u8      = 13;
-    pub const CTRL_Q: u8     = 17;
-    pub const CTRL_S: u8     = 19;
-    pub const CTRL_U: u8     = 21;
-    pub const BACKSPACE: u8 = 127;
-}
+pub const SHIFT: Modifiers = 1;
+pub const ALT: Modifiers   = 2;
+pub const CTRL: Modifiers  = 4;
+pub const META: Modifiers  = 8;
 
-#[derive(Debug, Copy, Clone)]
-pub enum Key {
-    Char(u8),
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum KeyCode {
+    // A printable character, or the letter of a Ctrl combination
+    Char(char),
+    Enter,
+    Tab,
+    Backspace,
     Esc,
+    Insert,
     Del,
     PageUp,
     PageDown,
@@ -47,80 +38,325 @@ pub enum Key {
     ArrowLeft,
     Home,
     End,
+    F(u8),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub code: KeyCode,
+    pub mods: Modifiers,
+}
+
+impl Key {
+    pub fn new(code: KeyCode, mods: Modifiers) -> Key {
+        Key {code: code, mods: mods}
+    }
+
+    pub fn with(self, mods: Modifiers) -> Key {
+        Key::new(self.code, self.mods | mods)
+    }
+
+    /// The character this key types, if it types one.
+    pub fn typed_char(self) -> Option<char> {
+        match (self.code, self.mods & !SHIFT) {
+            (KeyCode::Char(ch), 0) if !ch.is_control() => Some(ch),
+            _ => None,
+        }
+    }
+
+    /// Decode a character typed on its own. Control characters become
+    /// the named key or Ctrl combination that produces them.
+    pub fn from_char(ch: char) -> Key {
+        use self::KeyCode::*;
+
+        match ch {
+            '\r'           => Key::new(Enter, 0),
+            '\t'           => Key::new(Tab, 0),
+            '\x1b'         => Key::new(Esc, 0),
+            '\x7f'         => Key::new(Backspace, 0),
+            '\x00'         => Key::new(Char(' '), CTRL),
+            '\x01'...'\x1a' =>
+                Key::new(Char((ch as u8 + b'a' - 1) as char), CTRL),
+            '\x1c'...'\x1f' =>
+                Key::new(Char((ch as u8 + b'\\' - 0x1c) as char), CTRL),
+            _              => Key::new(Char(ch), 0),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Escape {
-    Char(u8),
+    Char(char),
+    // A character prefixed by ESC, as sent for Alt combinations
+    Alt(char),
     Esc,
+    // The bytes after CSI, ending with the final byte unless the
+    // sequence timed out
     CSI(Vec<u8>),
     SS3(u8),
-    InvalidEscape,
+    InvalidUtf8(Vec<u8>),
+    // Text pasted between CSI 200~ and CSI 201~ with bracketed paste on
+    Paste(String),
+}
+
+/// A key press, or text pasted into the terminal
+#[derive(Debug)]
+pub enum Input {
+    Key(Key),
+    Paste(String),
+}
+
+/// Split a CSI sequence such as `1;5C` into its numeric parameters and
+/// final byte.
+fn parse_csi(seq: &[u8]) -> (Vec<u32>, u8) {
+    let (final_byte, params) = match seq.split_last() {
+        Some((&b, params)) => (b, params),
+        None => return (vec![], 0),
+    };
+
+    let params = params.split(|&b| b == b';')
+        .map(|param| param.iter()
+             .filter(|b| (b'0'...b'9').contains(**b))
+             .fold(0, |n, &b| n * 10 + (b - b'0') as u32))
+        .collect();
+
+    (params, final_byte)
+}
+
+/// Decode the modifier parameter xterm sends with keys, which is one
+/// more than a bit set of Shift=1, Alt=2, Ctrl=4 and Meta=8.
+fn parse_modifiers(param: Option<&u32>) -> Modifiers {
+    param.map_or(0, |&m| m.saturating_sub(1) as Modifiers & (SHIFT | ALT | CTRL | META))
+}
+
+/// Key for the `n` of a `CSI n ~` sequence
+fn tilde_key(n: u32) -> Option<KeyCode> {
+    use self::KeyCode::*;
+
+    match n {
+        1 | 7   => Some(Home),
+        2       => Some(Insert),
+        3       => Some(Del),
+        4 | 8   => Some(End),
+        5       => Some(PageUp),
+        6       => Some(PageDown),
+        11...15 => Some(F((n - 10) as u8)),
+        17...21 => Some(F((n - 11) as u8)),
+        23 | 24 => Some(F((n - 12) as u8)),
+        _       => None,
+    }
+}
+
+/// Key for the final byte of a CSI or SS3 sequence other than `~`
+fn final_key(b: u8) -> Option<KeyCode> {
+    use self::KeyCode::*;
+
+    match b {
+        b'A'        => Some(ArrowUp),
+        b'B'        => Some(ArrowDown),
+        b'C'        => Some(ArrowRight),
+        b'D'        => Some(ArrowLeft),
+        b'H'        => Some(Home),
+        b'F'        => Some(End),
+        b'M'        => Some(Enter), // Keypad enter
+        b'P'...b'S' => Some(F(b - b'P' + 1)),
+        _           => None,
+    }
 }
 
 impl Escape {
     pub fn interpret(&self) -> Option<Key> {
+        use self::KeyCode::*;
+
         match self {
-            &Escape::Char(ch) => Some(Key::Char(ch)),
-            &Escape::Esc => Some(Key::Esc),
-            &Escape::CSI(ref seq) => match &seq[..] {
-                b"3~" => Some(Key::Del),
-                b"5~" => Some(Key::PageUp),
-                b"6~" => Some(Key::PageDown),
-                b"A"  => Some(Key::ArrowUp),
-                b"B"  => Some(Key::ArrowDown),
-                b"C"  => Some(Key::ArrowRight),
-                b"D"  => Some(Key::ArrowLeft),
-                b"H"  => Some(Key::Home),
-                b"F"  => Some(Key::End),
-                _     => None,
+            &Escape::Char(ch) => Some(Key::from_char(ch)),
+            &Escape::Alt(ch) => Some(Key::from_char(ch).with(ALT)),
+            &Escape::Esc => Some(Key::new(Esc, 0)),
+            &Escape::CSI(ref seq) => {
+                let (params, final_byte) = parse_csi(seq);
+
+                if !(64...126).contains(final_byte) {
+                    return None;
+                }
+
+                let mods = parse_modifiers(params.get(1));
+
+                let code = match final_byte {
+                    b'~' => tilde_key(params.get(0).map_or(0, |&n| n)),
+                    b'Z' => return Some(Key::new(Tab, SHIFT)),
+                    // rxvt sends Shift+arrows as lower case finals
+                    b'a'...b'd' => return final_key(final_byte - b'a' + b'A')
+                        .map(|code| Key::new(code, SHIFT)),
+                    _ => final_key(final_byte),
+                };
+
+                code.map(|code| Key::new(code, mods))
             },
             &Escape::SS3(ch) => match ch {
-                b'H'  => Some(Key::Home),
-                b'F'  => Some(Key::End),
-                _     => None,
+                // rxvt sends Ctrl+arrows as lower case finals
+                b'a'...b'd' => final_key(ch - b'a' + b'A')
+                    .map(|code| Key::new(code, CTRL)),
+                _ => final_key(ch).map(|code| Key::new(code, 0)),
             },
-            &Escape::InvalidEscape => None,
+            &Escape::InvalidUtf8(_) | &Escape::Paste(_) => None,
         }
     }
 }
 
-fn read_csi(stream: &mut io::Read) -> io::Result<Escape> {
-    let mut buf = vec![];
+/// How many timeouts in a row end a paste whose end never arrives
+const PASTE_TIMEOUTS: usize = 10;
 
-    loop {
-        let byte = match maybe_read_byte(stream)? {
-            Some(b) => b,
-            None    => return Ok(Escape::Esc), // This wasn't a real escape sequence
-        };
+/// Reads keys and escape sequences from a stream. A byte read too far,
+/// such as one that cut an invalid UTF-8 sequence short, is kept to
+/// start the next read.
+#[derive(Debug, Default)]
+pub struct EscapeReader {
+    unread: VecDeque<u8>,
+}
+
+impl EscapeReader {
+    fn read_byte(&mut self, stream: &mut io::Read) -> io::Result<Option<u8>> {
+        match self.unread.pop_front() {
+            Some(b) => Ok(Some(b)),
+            None => maybe_read_byte(stream),
+        }
+    }
+
+    fn read_csi(&mut self, stream: &mut io::Read) -> io::Result<Escape> {
+        let mut buf = vec![];
+
+        loop {
+            let byte = match self.read_byte(stream)? {
+                Some(b) => b,
+                // This wasn't a real escape sequence
+                None if buf.is_empty() => return Ok(Escape::Alt('[')),
+                // Cut short, so it has no final byte and means no key
+                None => break,
+            };
 
-        buf.push(byte);
+            buf.push(byte);
 
-        if (64...126).contains(byte) {
-            break;              // Final character
+            if (64...126).contains(byte) {
+                break;              // Final character
+            }
         }
+
+        Ok(Escape::CSI(buf))
     }
 
-    Ok(Escape::CSI(buf))
-}
+    /// Read pasted text up to the `CSI 201~` that ends it. Bytes that
+    /// aren't valid UTF-8 are replaced.
+    fn read_paste(&mut self, stream: &mut io::Read) -> io::Result<Escape> {
+        const END: &'static [u8] = b"\x1b[201~";
+
+        let mut buf: Vec<u8> = self.unread.drain(..).collect();
+        let mut chunk = [0u8; 4096];
+        let mut timeouts = 0;
+        // Where END may start in what hasn't been searched yet
+        let mut start = 0;
+
+        loop {
+            if let Some(i) = buf[start..].windows(END.len()).position(|w| w == END) {
+                // Keys typed right after the paste are read next
+                self.unread.extend(buf.drain(start + i..).skip(END.len()));
+                break;
+            }
+
+            if timeouts == PASTE_TIMEOUTS {
+                break;
+            }
 
-fn read_ss3(stream: &mut io::Read) -> io::Result<Escape> {
-    match maybe_read_byte(stream)? {
-        Some(byte)  => Ok(Escape::SS3(byte)),
-        None        => Ok(Escape::Esc), // Not a real escape sequence
+            start = buf.len().saturating_sub(END.len() - 1);
+
+            match stream.read(&mut chunk) {
+                Ok(0) => timeouts += 1,
+                Ok(n) => {
+                    buf.extend(&chunk[..n]);
+                    timeouts = 0;
+                },
+                // Don't lose the paste to SIGWINCH
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Escape::Paste(String::from_utf8_lossy(&buf).into_owned()))
     }
-}
 
-pub fn read_escape(stream: &mut io::Read) -> io::Result<Escape> {
-    match read_byte(stream)? {
-        // Escape sequence
-        0x1b => match maybe_read_byte(stream)? {
-            Some(b'[') => read_csi(stream), // Control sequence initiator
-            Some(b'O') => read_ss3(stream), // Single shift three
-            None => Ok(Escape::Esc),        // Plain old escape
-            _ => Ok(Escape::InvalidEscape), // Invalid
-        },
-        // Normal character entry
-        byte => Ok(Escape::Char(byte)),
+    fn read_ss3(&mut self, stream: &mut io::Read) -> io::Result<Escape> {
+        match self.read_byte(stream)? {
+            Some(byte)  => Ok(Escape::SS3(byte)),
+            None        => Ok(Escape::Alt('O')), // Not a real escape sequence
+        }
+    }
+
+    /// Read the rest of a UTF-8 sequence whose first byte is `lead`. A
+    /// byte that can't continue the sequence ends it and is left for the
+    /// next read.
+    fn read_utf8(&mut self, stream: &mut io::Read, lead: u8)
+            -> io::Result<Result<char, Vec<u8>>> {
+        let len = match lead {
+            0x00...0x7f => return Ok(Ok(lead as char)),
+            0xc2...0xdf => 2,
+            0xe0...0xef => 3,
+            0xf0...0xf4 => 4,
+            _           => return Ok(Err(vec![lead])),
+        };
+
+        let mut buf = vec![lead];
+
+        while buf.len() < len {
+            match self.read_byte(stream)? {
+                Some(b) if b & 0xc0 == 0x80 => buf.push(b),
+                Some(b) => {
+                    self.unread.push_front(b);
+                    break;
+                },
+                None    => break, // Truncated sequence
+            }
+        }
+
+        // Also rejects overlong forms and surrogates
+        let ch = str::from_utf8(&buf).ok()
+            .and_then(|s| s.chars().next());
+
+        match ch {
+            Some(ch) => Ok(Ok(ch)),
+            None => Ok(Err(buf)),
+        }
+    }
+
+    /// Read a key or escape sequence. Returns `None` if no input shows
+    /// up before the stream times out.
+    pub fn read_escape(&mut self, stream: &mut io::Read) -> io::Result<Option<Escape>> {
+        let first = match self.read_byte(stream)? {
+            Some(b) => b,
+            None    => return Ok(None),
+        };
+
+        let escape = match first {
+            // Escape sequence
+            0x1b => match self.read_byte(stream)? {
+                // Control sequence initiator
+                Some(b'[') => match self.read_csi(stream)? {
+                    Escape::CSI(ref seq) if seq == b"200~" => self.read_paste(stream)?,
+                    escape => escape,
+                },
+                Some(b'O') => self.read_ss3(stream)?, // Single shift three
+                None => Escape::Esc,                  // Plain old escape
+                // Alt combination
+                Some(byte) => match self.read_utf8(stream, byte)? {
+                    Ok(ch) => Escape::Alt(ch),
+                    Err(bytes) => Escape::InvalidUtf8(bytes),
+                },
+            },
+            // Normal character entry
+            byte => match self.read_utf8(stream, byte)? {
+                Ok(ch) => Escape::Char(ch),
+                Err(bytes) => Escape::InvalidUtf8(bytes),
+            },
+        };
+
+        Ok(Some(escape))
     }
 }
@@ -0,0 +1,854 @@
+//! A small backtracking regular expression engine working on chars.
+//!
+//! Supports literals, `.`, classes such as `[a-z]`, `[^0-9]`, `\d`, `\w`
+//! and `\s`, the anchors `^`, `$` and `\b`, groups `(...)` and `(?:...)`,
+//! alternation `|`, and the repetitions `*`, `+`, `?` and `{n,m}`, which
+//! may be made lazy with a trailing `?`. Positions are char indices.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt;
+
+use unicode::is_word_char;
+
+// Bounds counted repetitions so that a pattern can't blow up the program
+const MAX_REPEAT: u32 = 1000;
+// Bounds the whole program, as nested repetitions multiply
+const MAX_PROG_LEN: usize = 10000;
+// Most bits to spend remembering visited states, 4 MiB. Longer lines
+// remember only the states actually visited.
+const MAX_VISITED_BITS: usize = 1 << 25;
+
+#[derive(Debug, Clone)]
+struct Class {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+}
+
+impl Class {
+    fn matches(&self, ch: char) -> bool {
+        let found = self.ranges.iter().any(|&(lo, hi)| lo <= ch && ch <= hi);
+        found != self.negated
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Class),
+    // A class such as \w that isn't expressed as ranges
+    Perl(char),
+    LineStart,
+    LineEnd,
+    WordBoundary(bool),
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+        greedy: bool,
+    },
+}
+
+/// An error in a pattern, with the char position it was found at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub pos: usize,
+    pub msg: &'static str,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.msg, self.pos + 1)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, msg: &'static str) -> Result<T, Error> {
+        Err(Error {pos: self.pos, msg: msg})
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, ch: char) -> bool {
+        if self.peek() == Some(ch) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_alternation(&mut self) -> Result<Node, Error> {
+        let mut alternatives = vec![self.parse_concat()?];
+
+        while self.eat('|') {
+            alternatives.push(self.parse_concat()?);
+        }
+
+        if alternatives.len() == 1 {
+            Ok(alternatives.pop().unwrap())
+        } else {
+            Ok(Node::Alternate(alternatives))
+        }
+    }
+
+    fn parse_concat(&mut self) -> Result<Node, Error> {
+        let mut nodes = vec![];
+
+        loop {
+            match self.peek() {
+                None | Some('|') | Some(')') => break,
+                _ => {
+                    let atom = self.parse_atom()?;
+                    let node = self.parse_repeat(atom)?;
+                    nodes.push(node);
+                },
+            }
+        }
+
+        match nodes.len() {
+            0 => Ok(Node::Empty),
+            1 => Ok(nodes.pop().unwrap()),
+            _ => Ok(Node::Concat(nodes)),
+        }
+    }
+
+    fn parse_number(&mut self) -> Option<u32> {
+        let start = self.pos;
+
+        while self.peek().map_or(false, |ch| ch.is_digit(10)) {
+            self.pos += 1;
+        }
+
+        let digits: String = self.chars[start..self.pos].iter().cloned().collect();
+        digits.parse().ok()
+    }
+
+    /// Parse the `{n}`, `{n,}` or `{n,m}` after an atom. Returns `None`
+    /// if the brace doesn't start a valid repetition, in which case it
+    /// is taken literally.
+    fn parse_counts(&mut self) -> Option<(u32, Option<u32>)> {
+        let start = self.pos;
+        self.pos += 1;
+
+        let result = match self.parse_number() {
+            Some(min) => if self.eat('}') {
+                Some((min, Some(min)))
+            } else if self.eat(',') {
+                if self.eat('}') {
+                    Some((min, None))
+                } else {
+                    let max = self.parse_number();
+
+                    if max.is_some() && self.eat('}') {
+                        Some((min, max))
+                    } else {
+                        None
+                    }
+                }
+            } else {
+                None
+            },
+            None => None,
+        };
+
+        if result.is_none() {
+            self.pos = start;
+        }
+
+        result
+    }
+
+    fn parse_repeat(&mut self, mut atom: Node) -> Result<Node, Error> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => { self.pos += 1; (0, None) },
+                Some('+') => { self.pos += 1; (1, None) },
+                Some('?') => { self.pos += 1; (0, Some(1)) },
+                Some('{') => match self.parse_counts() {
+                    Some(counts) => counts,
+                    None => return Ok(atom),
+                },
+                _ => return Ok(atom),
+            };
+
+            if max.map_or(false, |max| max < min) {
+                return self.error("bad repetition count");
+            }
+
+            if min > MAX_REPEAT || max.map_or(false, |max| max > MAX_REPEAT) {
+                return self.error("repetition count too large");
+            }
+
+            match atom {
+                Node::Empty | Node::LineStart | Node::LineEnd
+                    | Node::WordBoundary(_) | Node::Repeat {..} =>
+                        return self.error("nothing to repeat"),
+                _ => (),
+            }
+
+            let greedy = !self.eat('?');
+
+            atom = Node::Repeat {
+                node: Box::new(atom),
+                min: min,
+                max: max,
+                greedy: greedy,
+            };
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<Node, Error> {
+        let ch = match self.peek() {
+            Some(ch) => ch,
+            None => return self.error("trailing backslash"),
+        };
+        self.pos += 1;
+
+        Ok(match ch {
+            'd' | 'D' | 'w' | 'W' | 's' | 'S' => Node::Perl(ch),
+            'b' => Node::WordBoundary(true),
+            'B' => Node::WordBoundary(false),
+            't' => Node::Char('\t'),
+            'n' => Node::Char('\n'),
+            _ if ch.is_alphanumeric() => {
+                self.pos -= 1;
+                return self.error("unknown escape");
+            },
+            _ => Node::Char(ch),
+        })
+    }
+
+    /// Parse a class after its opening `[`.
+    fn parse_class(&mut self) -> Result<Node, Error> {
+        let start = self.pos - 1;
+        let mut class = Class {negated: self.eat('^'), ranges: vec![]};
+        let first = self.pos;
+
+        loop {
+            let ch = match self.peek() {
+                Some(']') if self.pos > first => {
+                    self.pos += 1;
+                    return Ok(Node::Class(class));
+                },
+                Some(ch) => ch,
+                None => {
+                    self.pos = start;
+                    return self.error("unclosed [");
+                },
+            };
+            self.pos += 1;
+
+            let lo = if ch == '\\' {
+                match self.peek() {
+                    Some(esc) => {
+                        self.pos += 1;
+
+                        match esc {
+                            'd' => { class.ranges.push(('0', '9')); continue; },
+                            'w' => {
+                                class.ranges.extend_from_slice(
+                                    &[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')]);
+                                continue;
+                            },
+                            's' => {
+                                class.ranges.extend_from_slice(
+                                    &[(' ', ' '), ('\t', '\r')]);
+                                continue;
+                            },
+                            't' => '\t',
+                            'n' => '\n',
+                            _ => esc,
+                        }
+                    },
+                    None => return self.error("trailing backslash"),
+                }
+            } else {
+                ch
+            };
+
+            let is_range = self.peek() == Some('-')
+                && self.chars.get(self.pos + 1).map_or(false, |&c| c != ']');
+
+            if is_range {
+                self.pos += 1;
+                let mut hi = self.chars[self.pos];
+                self.pos += 1;
+
+                if hi == '\\' {
+                    match self.peek() {
+                        Some(esc) => {
+                            hi = esc;
+                            self.pos += 1;
+                        },
+                        None => return self.error("trailing backslash"),
+                    }
+                }
+
+                if hi < lo {
+                    return self.error("bad class range");
+                }
+
+                class.ranges.push((lo, hi));
+            } else {
+                class.ranges.push((lo, lo));
+            }
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, Error> {
+        let ch = self.peek().unwrap();
+        self.pos += 1;
+
+        match ch {
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::LineStart),
+            '$' => Ok(Node::LineEnd),
+            '\\' => self.parse_escape(),
+            '[' => self.parse_class(),
+            '(' => {
+                let start = self.pos - 1;
+
+                let index = if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+
+                let node = self.parse_alternation()?;
+
+                if !self.eat(')') {
+                    self.pos = start;
+                    return self.error("unclosed (");
+                }
+
+                Ok(Node::Group(Box::new(node), index))
+            },
+            '*' | '+' | '?' => {
+                self.pos -= 1;
+                self.error("nothing to repeat")
+            },
+            _ => Ok(Node::Char(ch)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Perl(char),
+    LineStart,
+    LineEnd,
+    WordBoundary(bool),
+    // Try the first branch, then the second
+    Split(usize, usize),
+    Jump(usize),
+    Save(usize),
+    Match,
+}
+
+struct Compiler {
+    prog: Vec<Inst>,
+}
+
+impl Compiler {
+    fn emit(&mut self, inst: Inst) -> Result<usize, Error> {
+        if self.prog.len() == MAX_PROG_LEN {
+            return Err(Error {pos: 0, msg: "pattern too large"});
+        }
+
+        self.prog.push(inst);
+        Ok(self.prog.len() - 1)
+    }
+
+    /// Emit a split whose targets are filled in later
+    fn emit_split(&mut self) -> Result<usize, Error> {
+        self.emit(Inst::Split(0, 0))
+    }
+
+    fn patch_split(&mut self, at: usize, first: usize, second: usize) {
+        self.prog[at] = Inst::Split(first, second);
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), Error> {
+        match *node {
+            Node::Empty => (),
+            Node::Char(ch) => { self.emit(Inst::Char(ch))?; },
+            Node::Any => { self.emit(Inst::Any)?; },
+            Node::Class(ref class) => { self.emit(Inst::Class(class.clone()))?; },
+            Node::Perl(ch) => { self.emit(Inst::Perl(ch))?; },
+            Node::LineStart => { self.emit(Inst::LineStart)?; },
+            Node::LineEnd => { self.emit(Inst::LineEnd)?; },
+            Node::WordBoundary(b) => { self.emit(Inst::WordBoundary(b))?; },
+            Node::Group(ref node, None) => self.compile(node)?,
+            Node::Group(ref node, Some(index)) => {
+                self.emit(Inst::Save(index * 2))?;
+                self.compile(node)?;
+                self.emit(Inst::Save(index * 2 + 1))?;
+            },
+            Node::Concat(ref nodes) => {
+                for node in nodes {
+                    self.compile(node)?;
+                }
+            },
+            Node::Alternate(ref nodes) => {
+                let mut jumps = vec![];
+
+                for (i, node) in nodes.iter().enumerate() {
+                    if i + 1 == nodes.len() {
+                        self.compile(node)?;
+                    } else {
+                        let split = self.emit_split()?;
+                        self.compile(node)?;
+                        jumps.push(self.emit(Inst::Jump(0))?);
+
+                        let next = self.prog.len();
+                        self.patch_split(split, split + 1, next);
+                    }
+                }
+
+                let end = self.prog.len();
+
+                for jump in jumps {
+                    self.prog[jump] = Inst::Jump(end);
+                }
+            },
+            Node::Repeat {ref node, min, max, greedy} => {
+                for _ in 0..min {
+                    self.compile(node)?;
+                }
+
+                match max {
+                    None => {
+                        let split = self.emit_split()?;
+                        self.compile(node)?;
+                        self.emit(Inst::Jump(split))?;
+
+                        let end = self.prog.len();
+                        self.patch_branch(split, end, greedy);
+                    },
+                    Some(max) => {
+                        let mut splits = vec![];
+
+                        for _ in min..max {
+                            splits.push(self.emit_split()?);
+                            self.compile(node)?;
+                        }
+
+                        let end = self.prog.len();
+
+                        for split in splits {
+                            self.patch_branch(split, end, greedy);
+                        }
+                    },
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Make the split at `at` choose between continuing with the
+    /// repeated node right after it and skipping to `skip`.
+    fn patch_branch(&mut self, at: usize, skip: usize, greedy: bool) {
+        if greedy {
+            self.patch_split(at, at + 1, skip);
+        } else {
+            self.patch_split(at, skip, at + 1);
+        }
+    }
+}
+
+/// Where a match and its groups were found, in char indices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Captures {
+    slots: Vec<Option<usize>>,
+}
+
+impl Captures {
+    pub fn start(&self) -> usize {
+        self.slots[0].unwrap()
+    }
+
+    pub fn end(&self) -> usize {
+        self.slots[1].unwrap()
+    }
+
+    /// The span of group `i`, if it took part in the match. Group 0 is
+    /// the whole match.
+    pub fn group(&self, i: usize) -> Option<(usize, usize)> {
+        match (self.slots.get(2 * i), self.slots.get(2 * i + 1)) {
+            (Some(&Some(start)), Some(&Some(end))) => Some((start, end)),
+            _ => None,
+        }
+    }
+
+    /// The number of groups, counting the whole match
+    pub fn len(&self) -> usize {
+        self.slots.len() / 2
+    }
+}
+
+/// Quote the metacharacters of `text` so that it matches literally.
+pub fn escape(text: &str) -> String {
+    let mut result = String::new();
+
+    for ch in text.chars() {
+        if "\\.+*?()|[]{}^$".contains(ch) {
+            result.push('\\');
+        }
+        result.push(ch);
+    }
+
+    result
+}
+
+#[derive(Debug)]
+pub struct Regex {
+    prog: Vec<Inst>,
+    groups: usize,
+    ignore_case: bool,
+    // Kept between searches to save allocating it for every line
+    visited: RefCell<Visited>,
+}
+
+/// Memory of which (instruction, position) pairs are known to fail
+#[derive(Debug, Default)]
+struct Visited {
+    bits: Vec<u64>,
+    // Indices of the words of `bits` that aren't zero, so that they can
+    // be cleared without going over all of them
+    touched: Vec<usize>,
+    // Used instead of `bits` when they would be over MAX_VISITED_BITS
+    sparse: Option<HashSet<usize>>,
+    width: usize,
+}
+
+impl Visited {
+    /// Forget everything, making room for a new text. This only costs
+    /// as much as the last search did, so searching a line again and
+    /// again is cheap.
+    fn reset(&mut self, prog_len: usize, text_len: usize) {
+        for &word in &self.touched {
+            self.bits[word] = 0;
+        }
+        self.touched.clear();
+
+        self.width = text_len + 1;
+        let states = prog_len.saturating_mul(self.width);
+
+        if states <= MAX_VISITED_BITS {
+            let words = (states + 63) / 64;
+
+            if self.bits.len() < words {
+                self.bits.resize(words, 0);
+            }
+            self.sparse = None;
+        } else {
+            self.sparse = Some(HashSet::new());
+        }
+    }
+
+    /// Mark a state as visited, returning whether it already was.
+    fn visit(&mut self, pc: usize, pos: usize) -> bool {
+        let i = pc * self.width + pos;
+
+        if let Some(ref mut sparse) = self.sparse {
+            return !sparse.insert(i);
+        }
+
+        let (word, bit) = (i / 64, 1u64 << (i % 64));
+
+        if self.bits[word] == 0 {
+            self.touched.push(word);
+        }
+
+        let seen = self.bits[word] & bit != 0;
+        self.bits[word] |= bit;
+        seen
+    }
+}
+
+impl Regex {
+    pub fn with_options(pattern: &str, ignore_case: bool) -> Result<Regex, Error> {
+        let mut parser = Parser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            groups: 0,
+        };
+
+        let node = parser.parse_alternation()?;
+
+        if parser.pos < parser.chars.len() {
+            // Only a stray ')' stops the parser early
+            return parser.error("unmatched )");
+        }
+
+        let mut compiler = Compiler {prog: vec![]};
+        compiler.emit(Inst::Save(0))?;
+        compiler.compile(&node)?;
+        compiler.emit(Inst::Save(1))?;
+        compiler.emit(Inst::Match)?;
+
+        Ok(Regex {
+            prog: compiler.prog,
+            groups: parser.groups,
+            ignore_case: ignore_case,
+            visited: RefCell::new(Visited::default()),
+        })
+    }
+
+    fn char_matches(&self, expected: char, ch: char) -> bool {
+        if expected == ch {
+            return true;
+        }
+
+        self.ignore_case && expected.to_lowercase().eq(ch.to_lowercase())
+    }
+
+    fn class_matches(&self, class: &Class, ch: char) -> bool {
+        if class.matches(ch) {
+            return true;
+        }
+
+        self.ignore_case
+            && (ch.to_lowercase().any(|c| class.matches(c))
+                || ch.to_uppercase().any(|c| class.matches(c)))
+    }
+
+    /// Try to match starting exactly at `start`.
+    fn run(&self, text: &[char], start: usize, visited: &mut Visited) -> Option<Captures> {
+        let mut slots = vec![None; (self.groups + 1) * 2];
+        // Pending branches, along with slot values to restore
+        let mut stack: Vec<(usize, usize, Option<(usize, Option<usize>)>)> =
+            vec![(0, start, None)];
+
+        while let Some((mut pc, mut pos, restore)) = stack.pop() {
+            if let Some((slot, value)) = restore {
+                slots[slot] = value;
+                continue;
+            }
+
+            loop {
+                if visited.visit(pc, pos) {
+                    break;
+                }
+
+                let at_word = |i: usize| text.get(i).map_or(false, |&ch| is_word_char(ch));
+
+                match self.prog[pc] {
+                    Inst::Char(expected) => match text.get(pos) {
+                        Some(&ch) if self.char_matches(expected, ch) => {
+                            pc += 1;
+                            pos += 1;
+                        },
+                        _ => break,
+                    },
+                    Inst::Any => if pos < text.len() {
+                        pc += 1;
+                        pos += 1;
+                    } else {
+                        break;
+                    },
+                    Inst::Class(ref class) => match text.get(pos) {
+                        Some(&ch) if self.class_matches(class, ch) => {
+                            pc += 1;
+                            pos += 1;
+                        },
+                        _ => break,
+                    },
+                    Inst::Perl(kind) => {
+                        let matched = text.get(pos).map_or(false, |&ch| match kind {
+                            'd' => ch.is_digit(10),
+                            'D' => !ch.is_digit(10),
+                            'w' => is_word_char(ch),
+                            'W' => !is_word_char(ch),
+                            's' => ch.is_whitespace(),
+                            _ => !ch.is_whitespace(),
+                        });
+
+                        if !matched {
+                            break;
+                        }
+
+                        pc += 1;
+                        pos += 1;
+                    },
+                    Inst::LineStart => if pos == 0 { pc += 1 } else { break },
+                    Inst::LineEnd => if pos == text.len() { pc += 1 } else { break },
+                    Inst::WordBoundary(expected) => {
+                        let boundary = pos > 0 && at_word(pos - 1) != at_word(pos)
+                            || pos == 0 && at_word(0);
+
+                        if boundary != expected {
+                            break;
+                        }
+
+                        pc += 1;
+                    },
+                    Inst::Split(first, second) => {
+                        stack.push((second, pos, None));
+                        pc = first;
+                    },
+                    Inst::Jump(target) => pc = target,
+                    Inst::Save(slot) => {
+                        stack.push((0, 0, Some((slot, slots[slot]))));
+                        slots[slot] = Some(pos);
+                        pc += 1;
+                    },
+                    Inst::Match => return Some(Captures {slots: slots}),
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Find the first match starting at or after `from`.
+    pub fn find_from(&self, text: &[char], from: usize) -> Option<Captures> {
+        let mut visited = self.visited.borrow_mut();
+        visited.reset(self.prog.len(), text.len());
+
+        (from..text.len() + 1)
+            .filter_map(|start| self.run(text, start, &mut visited))
+            .next()
+    }
+
+    /// Find the last match starting before `to`.
+    pub fn rfind_to(&self, text: &[char], to: usize) -> Option<Captures> {
+        let mut visited = self.visited.borrow_mut();
+        visited.reset(self.prog.len(), text.len());
+        let to = if to > text.len() + 1 { text.len() + 1 } else { to };
+
+        (0..to).rev()
+            .filter_map(|start| self.run(text, start, &mut visited))
+            .next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_PROG_LEN, Regex, Visited};
+
+    fn find(pattern: &str, text: &str) -> Option<(usize, usize)> {
+        find_with(pattern, text, false)
+    }
+
+    fn find_with(pattern: &str, text: &str, ignore_case: bool) -> Option<(usize, usize)> {
+        let re = Regex::with_options(pattern, ignore_case).unwrap();
+        let chars: Vec<char> = text.chars().collect();
+        re.find_from(&chars, 0).map(|c| (c.start(), c.end()))
+    }
+
+    #[test]
+    fn alternation_prefers_the_first_branch() {
+        assert_eq!(find("a|ab", "ab"), Some((0, 1)));
+        assert_eq!(find("ab|a", "ab"), Some((0, 2)));
+        assert_eq!(find("x|bc|d", "abcd"), Some((1, 3)));
+    }
+
+    #[test]
+    fn greedy_and_lazy_repetition() {
+        assert_eq!(find("a+", "baaa"), Some((1, 4)));
+        assert_eq!(find("a+?", "baaa"), Some((1, 2)));
+        assert_eq!(find("a*", "baaa"), Some((0, 0)));
+        assert_eq!(find("<.*>", "<a><b>"), Some((0, 6)));
+        assert_eq!(find("<.*?>", "<a><b>"), Some((0, 3)));
+        assert_eq!(find("ab?", "ab"), Some((0, 2)));
+        assert_eq!(find("ab??", "ab"), Some((0, 1)));
+    }
+
+    #[test]
+    fn counted_repetition() {
+        assert_eq!(find("[0-9]{2,3}", "a12345"), Some((1, 4)));
+        assert_eq!(find("[0-9]{2}", "a1b23"), Some((3, 5)));
+        assert_eq!(find("x{2,}", "xxxx"), Some((0, 4)));
+        assert_eq!(find("x{2,3}?", "xxxx"), Some((0, 2)));
+        // Not a repetition, so taken literally
+        assert_eq!(find("a{,2}", "a{,2}"), Some((0, 5)));
+    }
+
+    #[test]
+    fn anchors_and_word_boundaries() {
+        assert_eq!(find("^b", "ab"), None);
+        assert_eq!(find("^a", "ab"), Some((0, 1)));
+        assert_eq!(find("b$", "abb"), Some((2, 3)));
+        assert_eq!(find("\\bfoo\\b", "foobar foo"), Some((7, 10)));
+        assert_eq!(find("\\Boo", "foo"), Some((1, 3)));
+    }
+
+    #[test]
+    fn groups() {
+        let re = Regex::with_options("(\\w+)=(\\w*)(x)?", false).unwrap();
+        let chars: Vec<char> = " k=v".chars().collect();
+        let c = re.find_from(&chars, 0).unwrap();
+        assert_eq!(c.len(), 4);
+        assert_eq!(c.group(1), Some((1, 2)));
+        assert_eq!(c.group(2), Some((3, 4)));
+        assert_eq!(c.group(3), None);
+
+        // A repeated group captures its last iteration
+        let re = Regex::with_options("(?:(a)|b)+", false).unwrap();
+        let chars: Vec<char> = "ab".chars().collect();
+        assert_eq!(re.find_from(&chars, 0).unwrap().group(1), Some((0, 1)));
+    }
+
+    #[test]
+    fn case_folding() {
+        assert_eq!(find_with("straße", "STRASSE Straße", true), Some((8, 14)));
+        assert_eq!(find_with("ÉTÉ", "été", true), Some((0, 3)));
+        assert_eq!(find_with("[a-c]+", "xABC", true), Some((1, 4)));
+        assert_eq!(find_with("abc", "ABC", false), None);
+    }
+
+    #[test]
+    fn rfind_to_finds_the_last_start() {
+        let re = Regex::with_options("\\d+", false).unwrap();
+        let chars: Vec<char> = "1 22 333".chars().collect();
+        // The closest start wins, even inside a longer match
+        assert_eq!(re.rfind_to(&chars, 5).map(|c| (c.start(), c.end())), Some((3, 4)));
+        assert_eq!(re.rfind_to(&chars, 1).map(|c| (c.start(), c.end())), Some((0, 1)));
+    }
+
+    #[test]
+    fn nested_repetitions_are_too_large() {
+        let err = Regex::with_options("((a{1000}){1000}){1000}", false).unwrap_err();
+        assert_eq!(err.msg, "pattern too large");
+    }
+
+    #[test]
+    fn long_lines_remember_only_visited_states() {
+        let mut visited = Visited::default();
+        visited.reset(MAX_PROG_LEN, 1_000_000);
+        assert!(visited.bits.is_empty());
+        assert!(!visited.visit(3, 500_000));
+        assert!(visited.visit(3, 500_000));
+
+        visited.reset(10, 10);
+        assert!(visited.sparse.is_none());
+        assert!(!visited.visit(3, 5));
+        assert!(visited.visit(3, 5));
+
+        // Only what was visited is cleared
+        visited.reset(10, 10);
+        assert!(visited.bits.iter().all(|&word| word == 0));
+        assert!(!visited.visit(3, 5));
+    }
+}
@@ -0,0 +1,297 @@
+use std::cmp;
+
+use regex::{self, Regex};
+use unicode::is_word_char;
+
+/// Returns the index in `chars` of the first `needle` starting at or
+/// after `from`.
+fn find_chars(chars: &[char], needle: &[char], from: usize) -> Option<usize> {
+    if from > chars.len() {
+        return None;
+    }
+
+    if needle.is_empty() {
+        return Some(from);
+    }
+
+    chars[from..].windows(needle.len())
+        .position(|window| window == needle)
+        .map(|i| from + i)
+}
+
+/// Returns the index in `chars` of the last `needle` starting before
+/// `to`, which must be above zero.
+fn rfind_chars(chars: &[char], needle: &[char], to: usize) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(cmp::min(to - 1, chars.len()));
+    }
+
+    let end = cmp::min(to - 1 + needle.len(), chars.len());
+
+    chars[..end].windows(needle.len())
+        .rposition(|window| window == needle)
+}
+
+/// A match within a line, in char columns.
+#[derive(Debug, Clone)]
+pub struct Match {
+    pub start: usize,
+    pub end: usize,
+    // The spans of the regex groups, starting with the whole match
+    groups: Vec<Option<(usize, usize)>>,
+}
+
+impl Match {
+    fn literal(start: usize, len: usize) -> Match {
+        Match {
+            start: start,
+            end: start + len,
+            groups: vec![Some((start, start + len))],
+        }
+    }
+
+    fn from_captures(caps: regex::Captures) -> Match {
+        Match {
+            start: caps.start(),
+            end: caps.end(),
+            groups: (0..caps.len()).map(|i| caps.group(i)).collect(),
+        }
+    }
+}
+
+/// How a query's text is interpreted.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    pub regex: bool,
+    pub ignore_case: bool,
+    // Ignore case unless the text has an uppercase letter
+    pub smart_case: bool,
+    // Only match whole words
+    pub whole_word: bool,
+}
+
+/// Whether `text` has an uppercase letter, not counting regex escapes
+/// such as `\W`.
+fn has_uppercase(text: &str, regex: bool) -> bool {
+    let mut chars = text.chars();
+
+    while let Some(ch) = chars.next() {
+        if regex && ch == '\\' {
+            chars.next();
+        } else if ch.is_uppercase() {
+            return true;
+        }
+    }
+
+    false
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Literal(Vec<char>),
+    Regex(Regex),
+}
+
+/// What the find prompt looks for: a literal string or a regex.
+#[derive(Debug)]
+pub struct Query {
+    matcher: Matcher,
+    // Whether `$1` in a replacement refers to a group
+    regex: bool,
+    whole_word: bool,
+}
+
+impl Query {
+    pub fn new(text: &str, options: Options) -> Result<Query, regex::Error> {
+        let ignore_case = options.ignore_case
+            || (options.smart_case && !has_uppercase(text, options.regex));
+
+        let matcher = if options.regex {
+            Matcher::Regex(Regex::with_options(text, ignore_case)?)
+        } else if ignore_case {
+            Matcher::Regex(Regex::with_options(&regex::escape(text), true)?)
+        } else {
+            Matcher::Literal(text.chars().collect())
+        };
+
+        Ok(Query {
+            matcher: matcher,
+            regex: options.regex,
+            whole_word: options.whole_word,
+        })
+    }
+
+    /// Whether `m` is allowed by the whole word option, that is, it
+    /// doesn't start or end in the middle of a word.
+    fn is_whole_word(&self, chars: &[char], m: &Match) -> bool {
+        if !self.whole_word {
+            return true;
+        }
+
+        let is_word_at = |i: usize| chars.get(i).map_or(false, |&ch| is_word_char(ch));
+        let splits_word = |i: usize| i > 0 && is_word_at(i - 1) && is_word_at(i);
+
+        !splits_word(m.start) && !splits_word(m.end)
+    }
+
+    /// A match of the text alone, at or after `from`
+    fn find_any(&self, chars: &[char], from: usize) -> Option<Match> {
+        match self.matcher {
+            Matcher::Literal(ref s) => find_chars(chars, s, from).map(|start| {
+                Match::literal(start, s.len())
+            }),
+            Matcher::Regex(ref re) => re.find_from(chars, from).map(Match::from_captures),
+        }
+    }
+
+    /// A match of the text alone, starting before `to`
+    fn rfind_any(&self, chars: &[char], to: usize) -> Option<Match> {
+        match self.matcher {
+            Matcher::Literal(ref s) => {
+                if to == 0 {
+                    return None;
+                }
+
+                rfind_chars(chars, s, to).map(|start| Match::literal(start, s.len()))
+            },
+            Matcher::Regex(ref re) => re.rfind_to(chars, to).map(Match::from_captures),
+        }
+    }
+
+    /// The first match in `line` that starts at or after `from`.
+    pub fn find(&self, line: &str, from: usize) -> Option<Match> {
+        let chars: Vec<char> = line.chars().collect();
+        self.find_in(&chars, from)
+    }
+
+    /// `find` for a line already split into chars
+    fn find_in(&self, chars: &[char], from: usize) -> Option<Match> {
+        let mut from = from;
+
+        while from <= chars.len() {
+            match self.find_any(chars, from) {
+                Some(m) => if self.is_whole_word(chars, &m) {
+                    return Some(m);
+                } else {
+                    from = m.start + 1;
+                },
+                None => break,
+            }
+        }
+
+        None
+    }
+
+    /// The last match in `line` that starts before `to`.
+    pub fn rfind(&self, line: &str, to: usize) -> Option<Match> {
+        let chars: Vec<char> = line.chars().collect();
+        let mut to = to;
+
+        while let Some(m) = self.rfind_any(&chars, to) {
+            if self.is_whole_word(&chars, &m) {
+                return Some(m);
+            }
+
+            to = m.start;
+        }
+
+        None
+    }
+
+    /// The matches in `line` from left to right, not overlapping. An
+    /// empty match right where the previous one ended is left out.
+    pub fn find_all(&self, line: &str) -> Vec<Match> {
+        let chars: Vec<char> = line.chars().collect();
+        let mut matches: Vec<Match> = vec![];
+        let mut from = 0;
+
+        while from <= chars.len() {
+            let m = match self.find_in(&chars, from) {
+                Some(m) => m,
+                None => break,
+            };
+
+            if m.start == m.end {
+                from = m.end + 1;
+
+                if matches.last().map_or(false, |last| last.end == m.start) {
+                    continue;
+                }
+            } else {
+                from = m.end;
+            }
+
+            matches.push(m);
+        }
+
+        matches
+    }
+
+    /// The text that should replace `m` in `line`. For a regex, `$1` or
+    /// `\1` in `replacement` stands for the text of the first group,
+    /// `$0` for the whole match, and `$$` or `\\` for a plain `$` or `\`.
+    pub fn expand(&self, line: &str, m: &Match, replacement: &str) -> String {
+        if !self.regex {
+            return replacement.to_owned();
+        }
+
+        let chars: Vec<char> = line.chars().collect();
+        let mut result = String::new();
+        let mut iter = replacement.chars().peekable();
+
+        while let Some(ch) = iter.next() {
+            if ch != '$' && ch != '\\' {
+                result.push(ch);
+                continue;
+            }
+
+            let next = iter.peek().cloned();
+
+            match next.and_then(|next| next.to_digit(10)) {
+                Some(i) => {
+                    iter.next();
+
+                    // A group that didn't take part expands to nothing
+                    if let Some(&Some((start, end))) = m.groups.get(i as usize) {
+                        result.extend(&chars[start..end]);
+                    }
+                },
+                None if next == Some(ch) => {
+                    iter.next();
+                    result.push(ch);
+                },
+                None => result.push(ch),
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Options, Query};
+
+    fn spans(query: &Query, line: &str) -> Vec<(usize, usize)> {
+        query.find_all(line).iter().map(|m| (m.start, m.end)).collect()
+    }
+
+    #[test]
+    fn find_all_literal() {
+        let query = Query::new("日本", Options::default()).unwrap();
+        assert_eq!(spans(&query, "日本日本 x日本"), [(0, 2), (2, 4), (6, 8)]);
+        assert_eq!(query.rfind("日本日本 x日本", 6).map(|m| m.start), Some(2));
+        assert_eq!(query.rfind("日本日本 x日本", 0).map(|m| m.start), None);
+    }
+
+    #[test]
+    fn find_all_regex() {
+        let options = Options {regex: true, ..Options::default()};
+        let query = Query::new("a*", options).unwrap();
+        assert_eq!(spans(&query, "baab"), [(0, 0), (1, 3), (4, 4)]);
+
+        let options = Options {whole_word: true, ..options};
+        let query = Query::new("ab", options).unwrap();
+        assert_eq!(spans(&query, "ab abc ab"), [(0, 2), (7, 9)]);
+    }
+}
@@ -0,0 +1,131 @@
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Run `command` with `sh -c`, feeding it `input` on stdin, and return
+/// what it writes to stdout. A command that fails is an error carrying
+/// the first line of its stderr.
+pub fn filter(command: &str, input: &str) -> io::Result<String> {
+    run_filter(command, input, None)
+}
+
+/// Like `filter`, but a command still running after `timeout` is killed
+/// and is an error of kind `TimedOut`.
+pub fn filter_timeout(command: &str, input: &str, timeout: Duration)
+        -> io::Result<String> {
+    run_filter(command, input, Some(timeout))
+}
+
+fn run_filter(command: &str, input: &str, timeout: Option<Duration>)
+        -> io::Result<String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Write and read from other threads so that a command which produces
+    // output before reading all of its input can't deadlock with us, and
+    // so that the child stays here to be killed.
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_owned();
+    thread::spawn(move || {
+        // The command may exit without reading everything
+        let _ = stdin.write_all(input.as_bytes());
+    });
+
+    let (sender, receiver) = mpsc::channel();
+    read_all(child.stdout.take().unwrap(), true, sender.clone());
+    read_all(child.stderr.take().unwrap(), false, sender);
+
+    let started = Instant::now();
+    let (mut stdout, mut stderr) = (None, None);
+
+    while stdout.is_none() || stderr.is_none() {
+        let received = match timeout {
+            Some(timeout) => {
+                let elapsed = started.elapsed();
+                if elapsed >= timeout {
+                    Err(mpsc::RecvTimeoutError::Timeout)
+                } else {
+                    receiver.recv_timeout(timeout - elapsed)
+                }
+            },
+            None => {
+                receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+            },
+        };
+
+        match received {
+            Ok((true, bytes)) => stdout = Some(bytes?),
+            Ok((false, bytes)) => stderr = Some(bytes?),
+            Err(_) => {
+                // Not yet reaped, so the pid can't belong to anything else
+                let _ = child.kill();
+                let _ = child.wait();
+
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                          format!("`{}` timed out", command)));
+            },
+        }
+    }
+
+    let status = child.wait()?;
+    let (stdout, stderr) = (stdout.unwrap(), stderr.unwrap());
+
+    if status.success() {
+        Ok(String::from_utf8_lossy(&stdout).into_owned())
+    } else {
+        let errors = String::from_utf8_lossy(&stderr);
+        let message = match errors.lines().next() {
+            Some(line) if !line.is_empty() => line.to_owned(),
+            _ => format!("`{}` failed: {}", command, status),
+        };
+
+        Err(io::Error::new(io::ErrorKind::Other, message))
+    }
+}
+
+/// Read all of `pipe` on another thread, and send it tagged with `tag`.
+fn read_all<R>(mut pipe: R, tag: bool,
+                sender: mpsc::Sender<(bool, io::Result<Vec<u8>>)>)
+        where R: Read + Send + 'static {
+    thread::spawn(move || {
+        let mut bytes = vec![];
+        let result = pipe.read_to_end(&mut bytes).map(|_| bytes);
+        let _ = sender.send((tag, result));
+    });
+}
+
+/// Run `command` with `sh -c`, feeding it `input` on stdin and ignoring
+/// its output.
+pub fn pipe_to(command: &str, input: &str) -> io::Result<()> {
+    // Tools such as xclip leave a process behind that keeps stdout open,
+    // so it must not be a pipe we wait on.
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        // The command may exit without reading everything
+        let _ = stdin.write_all(input.as_bytes());
+    }
+
+    let status = child.wait()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other,
+                           format!("`{}` failed: {}", command, status)))
+    }
+}
@@ -13,16 +13,43 @@ pub enum Highlight {
     Number,
 }
 
+/// SGR color codes for each kind of highlight
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Colors {
+    pub normal: u8,
+    pub comment: u8,
+    pub keyword1: u8,
+    pub keyword2: u8,
+    pub string: u8,
+    pub number: u8,
+    // Signs in the gutter
+    pub change: u8,
+}
+
+impl Default for Colors {
+    fn default() -> Colors {
+        Colors {
+            normal: 0,      // white
+            comment: 36,    // cyan
+            keyword1: 33,   // yellow
+            keyword2: 32,   // green
+            string: 35,     // magenta
+            number: 31,     // red
+            change: 33,     // yellow
+        }
+    }
+}
+
 impl Highlight {
-    pub fn color(self) -> u8 {
+    pub fn color(self, colors: &Colors) -> u8 {
         use syntax::Highlight::*;
         match self {
-            Comment | MultiLineComment => 36, // cyan
-            PrimaryKeyword => 33,             // yellow
-            SecondaryKeyword => 32,           // green
-            String => 35,                     // magenta
-            Number => 31,                     // red
-            Normal => 0,                      // white
+            Comment | MultiLineComment => colors.comment,
+            PrimaryKeyword => colors.keyword1,
+            SecondaryKeyword => colors.keyword2,
+            String => colors.string,
+            Number => colors.number,
+            Normal => colors.normal,
         }
     }
 }
@@ -32,6 +59,8 @@ pub struct Syntax {
     pub file_extensions: HashSet<String>,
     pub primary_keywords: HashSet<String>,
     pub secondary_keywords: HashSet<String>,
+    // What starts a comment running to the end of the line
+    pub line_comment: String,
 }
 
 fn peek<Iter, Item>(iter: &Iter, count: usize) -> Vec<Item>
@@ -216,6 +245,7 @@ pub fn make_rust_syntax() -> Syntax {
         secondary_keywords: string_set![
             "float", "str", "char", "bool", "f32", "f64",
         ],
+        line_comment: "//".to_owned(),
     };
 
     for prefix in &["u", "i"] {
@@ -0,0 +1,163 @@
+use editor::Pos;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EditKind {
+    Insert,
+    Delete,
+}
+
+/// A single primitive change to the buffer: `text` was inserted at
+/// `at`, or the text starting at `at` was deleted.
+#[derive(Debug, Clone)]
+pub struct Edit {
+    pub kind: EditKind,
+    pub at: Pos,
+    pub text: String,
+}
+
+/// Returns the position just past `text` if it were inserted at `at`.
+pub fn end_of(at: Pos, text: &str) -> Pos {
+    match text.rfind('\n') {
+        Some(i) => Pos {
+            x: text[i + 1..].chars().count(),
+            y: at.y + text.matches('\n').count(),
+        },
+        None => Pos {
+            x: at.x + text.chars().count(),
+            y: at.y,
+        },
+    }
+}
+
+impl Edit {
+    /// Position just past the end of the affected text.
+    pub fn end(&self) -> Pos {
+        end_of(self.at, &self.text)
+    }
+
+    /// Try to fold `next` into `self` so that a run of typing or
+    /// deleting is undone in one step.
+    fn merge(&mut self, next: &Edit) -> bool {
+        if self.kind != next.kind || next.text.contains('\n')
+                || self.text.ends_with('\n') {
+            return false;
+        }
+
+        match self.kind {
+            EditKind::Insert if next.at == self.end() => {
+                self.text.push_str(&next.text);
+                true
+            },
+            // Backspace: the new deletion ends where the last one began
+            EditKind::Delete if next.end() == self.at => {
+                self.text = next.text.clone() + &self.text;
+                self.at = next.at;
+                true
+            },
+            // Forward delete: the new deletion starts at the same place
+            EditKind::Delete if next.at == self.at => {
+                self.text.push_str(&next.text);
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+/// A group of edits that are undone and redone as a unit.
+#[derive(Debug)]
+pub struct Step {
+    pub edits: Vec<Edit>,
+    pub cursor_before: Pos,
+    pub cursor_after: Pos,
+    id: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct History {
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+    // Whether the next edit may be merged into the last step
+    open: bool,
+    // Set between begin_group and end_group
+    grouping: bool,
+    last_id: u64,
+}
+
+impl History {
+    /// Record an edit that has already been applied to the buffer.
+    pub fn record(&mut self, edit: Edit, cursor_before: Pos, cursor_after: Pos) {
+        self.redo.clear();
+
+        if self.open {
+            if let Some(step) = self.undo.last_mut() {
+                let merged = match step.edits.last_mut() {
+                    Some(last) => last.merge(&edit),
+                    None => false,
+                };
+
+                if merged {
+                    step.cursor_after = cursor_after;
+                    return;
+                }
+
+                if self.grouping {
+                    step.edits.push(edit);
+                    step.cursor_after = cursor_after;
+                    return;
+                }
+            }
+        }
+
+        self.open = self.grouping || !edit.text.contains('\n');
+        self.last_id += 1;
+        self.undo.push(Step {
+            edits: vec![edit],
+            cursor_before: cursor_before,
+            cursor_after: cursor_after,
+            id: self.last_id,
+        });
+    }
+
+    /// Identifies the current state of the buffer. Undoing or redoing
+    /// back to a state yields the same version again.
+    pub fn version(&self) -> u64 {
+        self.undo.last().map_or(0, |step| step.id)
+    }
+
+    /// Prevent the next edit from being merged into the current step.
+    pub fn seal(&mut self) {
+        self.open = false;
+    }
+
+    /// Start a step that every edit up to `end_group` is added to.
+    pub fn begin_group(&mut self) {
+        self.open = false;
+        self.grouping = true;
+    }
+
+    pub fn end_group(&mut self) {
+        self.open = false;
+        self.grouping = false;
+    }
+
+    pub fn take_undo(&mut self) -> Option<Step> {
+        self.open = false;
+        self.undo.pop()
+    }
+
+    pub fn take_redo(&mut self) -> Option<Step> {
+        self.open = false;
+        self.redo.pop()
+    }
+
+    /// Return a step taken with `take_undo` once it has been reverted.
+    pub fn push_undone(&mut self, step: Step) {
+        self.redo.push(step);
+    }
+
+    /// Return a step taken with `take_redo` once it has been reapplied.
+    pub fn push_redone(&mut self, step: Step) {
+        self.undo.push(step);
+    }
+}